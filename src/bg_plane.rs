@@ -1,4 +1,12 @@
 pub use super::bg_resources::*;
+pub use super::box_frame::*;
+pub use super::char_map::*;
+pub use super::map_stream::MapSource;
+use super::map_stream::MapStreamer;
+pub use super::markup::{MarkupError, MarkupErrorKind};
+use super::markup::{MarkupToken, parse_markup};
+pub use super::metatile::*;
+pub use super::number_format::*;
 
 #[inline(always)]
 fn u_mod(x: i32, p: i32) -> i32 {
@@ -7,6 +15,70 @@ fn u_mod(x: i32, p: i32) -> i32 {
 
 pub type DrawRects = Vec<([f64; 4], [f64; 4])>;
 
+// Attributes written along with the codes by the string APIs, None leaves
+// the attribute in the buffer as it is.
+#[derive(Clone, Copy)]
+struct TextAttributes {
+    palette: Option<BgPalette>,
+    symmetry: Option<BgSymmetry>,
+    priority: Option<BgPriority>,
+}
+
+impl From<Option<&CharAttributes>> for TextAttributes {
+    fn from(op_attributes: Option<&CharAttributes>) -> Self {
+        Self {
+            palette: op_attributes.map(|a| a.palette),
+            symmetry: op_attributes.map(|a| a.symmetry),
+            priority: op_attributes.map(|a| a.priority),
+        }
+    }
+}
+
+// Writing direction of the string APIs. A '\n' starts a new line below
+// (LeftToRight, RightToLeft) or to the left (TopToBottom) of the current one.
+// With a base_symmetry that rotates the tiles, TopToBottom lays out text
+// that reads horizontally on a rotated screen.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum TextDirection {
+    #[default]
+    LeftToRight,
+    RightToLeft,
+    TopToBottom,
+}
+
+// Which part of the cells flood_fill compares with the starting cell.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum FloodMatch {
+    Code,
+    Palette,
+    Symmetry,
+    #[default]
+    AChar,
+}
+
+impl FloodMatch {
+    fn matches(&self, a: &AChar, b: &AChar) -> bool {
+        match self {
+            FloodMatch::Code => a.code == b.code,
+            FloodMatch::Palette => a.palette == b.palette,
+            FloodMatch::Symmetry => a.symmetry == b.symmetry,
+            FloodMatch::AChar => a == b,
+        }
+    }
+}
+
+// Cell position of the string APIs, may step out of the buffer by one line
+// and is wrapped when cells are written. For RightToLeft x is the right end
+// and for TopToBottom the right end and top of the next glyph.
+// line_size is the extent of the current line across the writing direction.
+struct TextCursor {
+    x: i32,
+    y: i32,
+    line_x: i32,
+    line_y: i32,
+    line_size: i32,
+}
+
 pub struct BgPlane<'a> {
     resources: BgResources<'a>,
     buffer_rect_size: (i32, i32),
@@ -16,6 +88,16 @@ pub struct BgPlane<'a> {
     cur_idx: i32,
     pixel_scale: i32,
     draw_rects: DrawRects,
+    layer_priority: i32,
+    high_layer_priority: i32,
+    blend_mode: BlendMode,
+    opacity: u8,
+    window: Option<ClipWindow>,
+    mosaic: i32,
+    brightness: i32,
+    char_map: Rc<dyn CharMap + 'a>,
+    text_direction: TextDirection,
+    map_streamer: Option<MapStreamer<'a>>,
 }
 
 impl <'a> BgPlane<'a> {
@@ -40,6 +122,16 @@ impl <'a> BgPlane<'a> {
             cur_idx: 0,
             pixel_scale,
             draw_rects,
+            layer_priority: 0,
+            high_layer_priority: i32::MIN,
+            blend_mode: BlendMode::default(),
+            opacity: 255,
+            window: None,
+            mosaic: 1,
+            brightness: 0,
+            char_map: Rc::new(AsciiCharMap::new()),
+            text_direction: TextDirection::default(),
+            map_streamer: None,
         }
     }
 
@@ -92,8 +184,45 @@ impl <'a> BgPlane<'a> {
         self.resources.base_symmetry()
     }
 
+    pub const fn layer_priority(&self) -> i32 {
+        self.layer_priority
+    }
+
+    pub const fn high_layer_priority(&self) -> i32 {
+        self.high_layer_priority
+    }
+
+    pub const fn blend_mode(&self) -> BlendMode {
+        self.blend_mode
+    }
+
+    pub const fn opacity(&self) -> u8 {
+        self.opacity
+    }
+
+    pub fn window(&self) -> Option<&ClipWindow> {
+        self.window.as_ref()
+    }
+
+    pub const fn mosaic(&self) -> i32 {
+        self.mosaic
+    }
+
+    pub const fn brightness(&self) -> i32 {
+        self.brightness
+    }
+
+    pub fn char_map(&self) -> Rc<dyn CharMap + 'a> {
+        self.char_map.clone()
+    }
+
+    pub const fn text_direction(&self) -> TextDirection {
+        self.text_direction
+    }
+
     pub fn set_view_pos(&mut self, x: i32, y: i32) -> &mut Self {
         self.view_pos = (x, y);
+        self._stream_map();
         self
     }
 
@@ -112,6 +241,117 @@ impl <'a> BgPlane<'a> {
         self
     }
 
+    pub const fn symmetry_scope(&self) -> SymmetryScope {
+        self.resources.symmetry_scope()
+    }
+
+    // With SymmetryScope::Screen the draw_rects are in the rotated view
+    // (view_size transformed by base_symmetry) while view_pos, the cells
+    // and the window stay unrotated.
+    pub fn set_symmetry_scope(&mut self, symmetry_scope: SymmetryScope) -> &mut Self {
+        self.resources.set_symmetry_scope(symmetry_scope);
+        self
+    }
+
+    pub fn screen_symmetry(&self) -> BgSymmetry {
+        self.resources.screen_symmetry()
+    }
+
+    pub fn invalidate_all(&mut self) -> &mut Self {
+        self.resources.invalidate_all();
+        self
+    }
+
+    pub fn invalidate_rect(&mut self, x: i32, y: i32, w: i32, h: i32) -> &mut Self {
+        self.resources.invalidate_rect(x, y, w, h);
+        self
+    }
+
+    // Priorities of this plane in the Compositor, compared with
+    // ClassicSprite::priority (smaller is in front). The cells without
+    // the priority bit are placed at layer_priority, the others at
+    // high_layer_priority.
+    pub fn set_layer_priority(&mut self, layer_priority: i32) -> &mut Self {
+        self.layer_priority = layer_priority;
+        self
+    }
+
+    pub fn set_high_layer_priority(&mut self, high_layer_priority: i32) -> &mut Self {
+        self.high_layer_priority = high_layer_priority;
+        self
+    }
+
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) -> &mut Self {
+        self.blend_mode = blend_mode;
+        self
+    }
+
+    pub fn set_opacity(&mut self, opacity: u8) -> &mut Self {
+        self.opacity = opacity;
+        self
+    }
+
+    pub fn set_window(&mut self, window: Option<ClipWindow>) -> &mut Self {
+        self.window = window;
+        self
+    }
+
+    // Block size in view pixels, 1 for no mosaic.
+    pub fn set_mosaic(&mut self, mosaic: i32) -> &mut Self {
+        self.mosaic = mosaic.max(1);
+        self
+    }
+
+    // Used by put_string and the other string APIs.
+    pub fn set_char_map(&mut self, char_map: Rc<dyn CharMap + 'a>) -> &mut Self {
+        self.char_map = char_map;
+        self
+    }
+
+    pub fn set_text_direction(&mut self, text_direction: TextDirection) -> &mut Self {
+        self.text_direction = text_direction;
+        self
+    }
+
+    // Streams the map into the buffer as the view moves. view_pos is then
+    // a world position and the cells within margin around the view are
+    // kept loaded. The buffer is filled for the current view_pos here.
+    // Streamed cells are not recorded in the history.
+    pub fn set_map_source(&mut self, source: Rc<dyn MapSource + 'a>, margin: i32) -> &mut Self {
+        self.map_streamer = Some(MapStreamer::new(source, margin));
+        self._stream_map();
+        self
+    }
+
+    pub fn clear_map_source(&mut self) -> &mut Self {
+        self.map_streamer = None;
+        self
+    }
+
+    pub fn map_margin(&self) -> Option<i32> {
+        self.map_streamer.as_ref().map(|s| s.margin)
+    }
+
+    // The world cells currently in the buffer as x, y, w, h.
+    pub fn loaded_map_rect(&self) -> Option<[i32; 4]> {
+        self.map_streamer.as_ref().and_then(|s| s.loaded)
+    }
+
+    // Reloads all the cells around the view, e.g. after the map changed.
+    pub fn refresh_map(&mut self) -> &mut Self {
+        if let Some(streamer) = self.map_streamer.as_mut() {
+            streamer.loaded = None;
+        }
+        self._stream_map();
+        self
+    }
+
+    // -BRIGHTNESS_MAX (black) ..= BRIGHTNESS_MAX (white), applied in the Compositor.
+    pub fn set_brightness(&mut self, brightness: i32) -> &mut Self {
+        self.brightness = brightness.clamp(-BRIGHTNESS_MAX, BRIGHTNESS_MAX);
+        self
+    }
+
     pub fn read_achar(&self) -> AChar {
         self.resources.get_achar(self.cur_idx)
     }
@@ -357,40 +597,328 @@ impl <'a> BgPlane<'a> {
         self
     }
 
-    fn _set_string(&mut self, idx: i32, thestr: &str, op_attributes: Option<&CharAttributes>) -> i32 {
-        let mut idx = idx;
-        let mut dx = 0;
-        let linear_size = self.resources.linear_size();
-        if let Some(attributes) = op_attributes {
-            let mut achar = AChar {code: 0, palette: attributes.palette, symmetry: attributes.symmetry};
-            for character in thestr.chars() {
-                if character == '\n' {
-                    idx = idx - dx + self.buffer_rect_size.0;
-                    dx = 0;
-                    continue;
+    pub fn read_priority(&self) -> BgPriority {
+        self.resources.get_priority(self.cur_idx)
+    }
+
+    pub fn get_priority(&self, idx: i32) -> BgPriority {
+        self.resources.get_priority(idx)
+    }
+
+    pub fn get_priority_at(&self, x: i32, y: i32) -> BgPriority {
+        self.resources.get_priority_at(x, y)
+    }
+
+    pub fn put_priority(&mut self, priority: BgPriority) -> &mut Self {
+        self.resources.set_priority(self.cur_idx, priority);
+        self.cur_idx += 1;
+        self
+    }
+
+    pub fn put_priority_n(&mut self, priority: BgPriority, n: i32) -> &mut Self {
+        self.resources.set_priority_n(self.cur_idx, priority, n);
+        self.cur_idx += n;
+        self
+    }
+
+    pub fn set_priority(&mut self, idx: i32, priority: BgPriority) -> &mut Self {
+        self.resources.set_priority(idx, priority);
+        self
+    }
+
+    pub fn set_priority_n(&mut self, idx: i32, priority: BgPriority, n: i32) -> &mut Self {
+        self.resources.set_priority_n(idx, priority, n);
+        self
+    }
+
+    pub fn set_priority_at(&mut self, x: i32, y: i32, priority: BgPriority) -> &mut Self {
+        self.resources.set_priority_at(x, y, priority);
+        self
+    }
+
+    pub fn set_priority_n_at(&mut self, x: i32, y: i32, priority: BgPriority, n: i32) -> &mut Self {
+        self.resources.set_priority_n_at(x, y, priority, n);
+        self
+    }
+
+    pub fn fill_priority(&mut self, priority: BgPriority) -> &mut Self {
+        self.resources.fill_priority(priority);
+        self
+    }
+
+    pub fn get_rect(&self, x: i32, y: i32, w: i32, h: i32) -> Vec<AChar> {
+        self.resources.get_rect(x, y, w, h)
+    }
+
+    pub fn set_rect(&mut self, x: i32, y: i32, w: i32, h: i32, achars: &[AChar]) -> &mut Self {
+        self.resources.set_rect(x, y, w, h, achars);
+        self
+    }
+
+    pub fn copy_rect(&mut self, src_pos: (i32, i32), size: (i32, i32), dst_pos: (i32, i32)) -> &mut Self {
+        self.resources.copy_rect(src_pos, size, dst_pos);
+        self
+    }
+
+    pub fn copy_rect_from(&mut self, src: &BgPlane, src_pos: (i32, i32), size: (i32, i32), dst_pos: (i32, i32)) -> &mut Self {
+        self.resources.copy_rect_from(&src.resources, src_pos, size, dst_pos);
+        self
+    }
+
+    pub fn shift(&mut self, dx: i32, dy: i32, op_fill: Option<&AChar>) -> &mut Self {
+        self.resources.shift(dx, dy, op_fill);
+        self
+    }
+
+    pub fn fill_achar_rect(&mut self, x: i32, y: i32, w: i32, h: i32, achar: &AChar) -> &mut Self {
+        self.resources.fill_achar_rect(x, y, w, h, achar);
+        self
+    }
+
+    pub fn fill_attributes_rect(&mut self, x: i32, y: i32, w: i32, h: i32, attributes: &CharAttributes) -> &mut Self {
+        self.resources.fill_attributes_rect(x, y, w, h, attributes);
+        self
+    }
+
+    pub fn fill_code_rect<T: Into<BgCode>>(&mut self, x: i32, y: i32, w: i32, h: i32, code: T) -> &mut Self {
+        self.resources.fill_code_rect(x, y, w, h, code);
+        self
+    }
+
+    pub fn clear_rect(&mut self, x: i32, y: i32, w: i32, h: i32) -> &mut Self {
+        self.resources.clear_rect(x, y, w, h);
+        self
+    }
+
+    pub fn snapshot(&self) -> BgSnapshot {
+        self.resources.snapshot()
+    }
+
+    pub fn restore(&mut self, snapshot: &BgSnapshot) -> &mut Self {
+        self.resources.restore(snapshot);
+        self
+    }
+
+    pub fn enable_history(&mut self, limit: usize) -> &mut Self {
+        self.resources.enable_history(limit);
+        self
+    }
+
+    pub fn disable_history(&mut self) -> &mut Self {
+        self.resources.disable_history();
+        self
+    }
+
+    pub fn clear_history(&mut self) -> &mut Self {
+        self.resources.clear_history();
+        self
+    }
+
+    pub fn commit_undo_step(&mut self) -> &mut Self {
+        self.resources.commit_undo_step();
+        self
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.resources.can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.resources.can_redo()
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.resources.undo()
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.resources.redo()
+    }
+
+    fn _text_cursor(&self, idx: i32) -> TextCursor {
+        let width = self.resources.width();
+        let idx = u_mod(idx, self.resources.linear_size());
+        let (x, y) = (idx % width, idx / width);
+        TextCursor { x, y, line_x: x, line_y: y, line_size: 1 }
+    }
+
+    fn _cursor_idx(&self, cursor: &TextCursor) -> i32 {
+        let (width, height) = self.resources.rect_size();
+        let (mut x, mut y) = (cursor.x, cursor.y);
+        match self.text_direction {
+            TextDirection::LeftToRight => (),
+            TextDirection::RightToLeft => if x < 0 {
+                x += width;
+                y += 1;
+            },
+            TextDirection::TopToBottom => if y >= height {
+                x -= 1;
+                y -= height;
+            },
+        }
+        u_mod(x + y * width, self.resources.linear_size())
+    }
+
+    fn _stream_map(&mut self) {
+        let Some(streamer) = self.map_streamer.as_ref() else {
+            return;
+        };
+        let region = streamer.region(self.view_pos, self.view_size, self.resources.rect_size());
+        if streamer.loaded == Some(region) {
+            return;
+        }
+        let source = streamer.source.clone();
+        for (x, y, w) in streamer.exposed_runs(region) {
+            for cx in x..x + w {
+                let idx = self._wrapped_idx(cx, y);
+                self.resources.load_achar(idx, &source.achar_at(cx, y));
+            }
+        }
+        if let Some(streamer) = self.map_streamer.as_mut() {
+            streamer.loaded = Some(region);
+        }
+    }
+
+    #[inline(always)]
+    fn _wrapped_idx(&self, x: i32, y: i32) -> i32 {
+        let (width, height) = self.resources.rect_size();
+        u_mod(x, width) + u_mod(y, height) * width
+    }
+
+    fn _set_glyph_at(&mut self, x: i32, y: i32, glyph: &Glyph, attributes: &TextAttributes) {
+        let palette = glyph.palette.or(attributes.palette);
+        let symmetry = glyph.symmetry.or(attributes.symmetry);
+        for gy in 0..glyph.height() {
+            for gx in 0..glyph.width() {
+                let cell_idx = self._wrapped_idx(x + gx, y + gy);
+                self.set_code(cell_idx, glyph.code_at(gx, gy));
+                if let Some(palette) = palette {
+                    self.set_palette(cell_idx, palette);
+                }
+                if let Some(symmetry) = symmetry {
+                    self.set_symmetry(cell_idx, symmetry);
                 }
-                if (character as u32) < 0x80 {
-                    achar.code = character as u32;
-                    self.set_achar(idx, &achar);
+                if let Some(priority) = attributes.priority {
+                    self.set_priority(cell_idx, priority);
                 }
-                idx = (idx + 1) % linear_size;
-                dx += 1;
             }
-        } else {
-            for character in thestr.chars() {
-                if character == '\n' {
-                    idx = idx - dx + self.buffer_rect_size.0;
-                    dx = 0;
-                    continue;
+        }
+    }
+
+    // A glyph that does not fit in the rest of the row (column) goes to the
+    // start of the next line, beyond the largest glyph of the current one.
+    fn _place_glyph(&mut self, cursor: &mut TextCursor, op_glyph: Option<&Glyph>, attributes: &TextAttributes) {
+        let (width, height) = self.resources.rect_size();
+        let (glyph_w, glyph_h) = op_glyph.map_or((1, 1), |glyph| glyph.size);
+        let (left, top) = match self.text_direction {
+            TextDirection::LeftToRight => {
+                if cursor.x + glyph_w > width && cursor.x > 0 {
+                    cursor.x = 0;
+                    cursor.y += cursor.line_size;
+                    cursor.line_size = 1;
+                }
+                let pos = (cursor.x, cursor.y);
+                cursor.x += glyph_w;
+                cursor.line_size = cursor.line_size.max(glyph_h);
+                pos
+            }
+            TextDirection::RightToLeft => {
+                if cursor.x - glyph_w < -1 && cursor.x < width - 1 {
+                    cursor.x = width - 1;
+                    cursor.y += cursor.line_size;
+                    cursor.line_size = 1;
                 }
-                if (character as u32) < 0x80 {
-                    self.set_code(idx, character as u32);
+                let pos = (cursor.x - glyph_w + 1, cursor.y);
+                cursor.x -= glyph_w;
+                cursor.line_size = cursor.line_size.max(glyph_h);
+                pos
+            }
+            TextDirection::TopToBottom => {
+                if cursor.y + glyph_h > height && cursor.y > 0 {
+                    cursor.y = 0;
+                    cursor.x -= cursor.line_size;
+                    cursor.line_size = 1;
                 }
-                idx = (idx + 1) % linear_size;
-                dx += 1;
+                let pos = (cursor.x - glyph_w + 1, cursor.y);
+                cursor.y += glyph_h;
+                cursor.line_size = cursor.line_size.max(glyph_w);
+                pos
+            }
+        };
+        if let Some(glyph) = op_glyph {
+            self._set_glyph_at(left, top, glyph, attributes);
+        }
+    }
+
+    fn _new_line(&self, cursor: &mut TextCursor) {
+        match self.text_direction {
+            TextDirection::LeftToRight | TextDirection::RightToLeft => {
+                cursor.x = cursor.line_x;
+                cursor.y += cursor.line_size;
+            }
+            TextDirection::TopToBottom => {
+                cursor.x -= cursor.line_size;
+                cursor.y = cursor.line_y;
+            }
+        }
+        cursor.line_size = 1;
+    }
+
+    fn _write_text(&mut self, cursor: &mut TextCursor, thestr: &str, attributes: &TextAttributes) {
+        let char_map = self.char_map.clone();
+        for character in thestr.chars() {
+            if character == '\n' {
+                self._new_line(cursor);
+                continue;
             }
+            let op_glyph = char_map.glyph(character).or_else(|| char_map.missing_glyph());
+            self._place_glyph(cursor, op_glyph, attributes);
         }
-        idx
+    }
+
+    fn _set_string(&mut self, idx: i32, thestr: &str, op_attributes: Option<&CharAttributes>) -> i32 {
+        let mut cursor = self._text_cursor(idx);
+        self._write_text(&mut cursor, thestr, &TextAttributes::from(op_attributes));
+        self._cursor_idx(&cursor)
+    }
+
+    fn _set_markup(&mut self, idx: i32, text: &str, op_attributes: Option<&CharAttributes>) -> Result<i32, MarkupError> {
+        let tokens = parse_markup(text)?;
+        let base_attributes = TextAttributes::from(op_attributes);
+        let mut attributes = base_attributes;
+        let mut cursor = self._text_cursor(idx);
+        for token in tokens {
+            match token {
+                MarkupToken::Text(thestr) => self._write_text(&mut cursor, &thestr, &attributes),
+                MarkupToken::Palette(palette) => attributes.palette = Some(palette),
+                MarkupToken::Symmetry(symmetry) => attributes.symmetry = Some(symmetry),
+                MarkupToken::Reset => attributes = base_attributes,
+                MarkupToken::Move(x, y) => cursor = self._text_cursor(x + y * self.resources.width()),
+            }
+        }
+        Ok(self._cursor_idx(&cursor))
+    }
+
+    fn _set_glyph(&mut self, idx: i32, glyph: &Glyph, op_attributes: Option<&CharAttributes>) -> i32 {
+        let mut cursor = self._text_cursor(idx);
+        self._place_glyph(&mut cursor, Some(glyph), &TextAttributes::from(op_attributes));
+        self._cursor_idx(&cursor)
+    }
+
+    pub fn put_glyph(&mut self, glyph: &Glyph, op_attributes: Option<&CharAttributes>) -> &mut Self {
+        self.cur_idx = self._set_glyph(self.cur_idx, glyph, op_attributes);
+        self
+    }
+
+    pub fn set_glyph(&mut self, idx: i32, glyph: &Glyph, op_attributes: Option<&CharAttributes>) -> &mut Self {
+        self._set_glyph(idx, glyph, op_attributes);
+        self
+    }
+
+    pub fn set_glyph_at(&mut self, x: i32, y: i32, glyph: &Glyph, op_attributes: Option<&CharAttributes>) -> &mut Self {
+        let idx = x + y * self.buffer_rect_size.0;
+        self._set_glyph(idx, glyph, op_attributes);
+        self
     }
 
     pub fn put_string(&mut self, thestr: &str, op_attributes: Option<&CharAttributes>) -> &mut Self {
@@ -409,10 +937,158 @@ impl <'a> BgPlane<'a> {
         self
     }
 
+    // As put_string, with inline markup:
+    //   {p:N}     palette N (decimal or 0x hex)
+    //   {s:NAME}  symmetry by name (FlipH, Rotate90, ...) or number 0..=7
+    //   {/}       back to op_attributes
+    //   {@X,Y}    move the cursor to the cell (X, Y)
+    //   {{ }}     literal braces
+    // Nothing is written when the markup is malformed.
+    pub fn put_markup(&mut self, text: &str, op_attributes: Option<&CharAttributes>) -> Result<&mut Self, MarkupError> {
+        self.cur_idx = self._set_markup(self.cur_idx, text, op_attributes)?;
+        Ok(self)
+    }
+
+    pub fn set_markup(&mut self, idx: i32, text: &str, op_attributes: Option<&CharAttributes>) -> Result<&mut Self, MarkupError> {
+        self._set_markup(idx, text, op_attributes)?;
+        Ok(self)
+    }
+
+    pub fn set_markup_at(&mut self, x: i32, y: i32, text: &str, op_attributes: Option<&CharAttributes>) -> Result<&mut Self, MarkupError> {
+        let idx = x + y * self.buffer_rect_size.0;
+        self._set_markup(idx, text, op_attributes)?;
+        Ok(self)
+    }
+
+    // Written through the char map, the cursor advances as put_string.
+    pub fn put_number(&mut self, value: i64, format: &NumberFormat, op_attributes: Option<&CharAttributes>) -> &mut Self {
+        self.put_string(&format.format(value), op_attributes)
+    }
+
+    pub fn set_number(&mut self, idx: i32, value: i64, format: &NumberFormat, op_attributes: Option<&CharAttributes>) -> &mut Self {
+        self.set_string(idx, &format.format(value), op_attributes)
+    }
+
+    pub fn set_number_at(&mut self, x: i32, y: i32, value: i64, format: &NumberFormat, op_attributes: Option<&CharAttributes>) -> &mut Self {
+        self.set_string_at(x, y, &format.format(value), op_attributes)
+    }
+
+    // w x h cells including the frame, wrapped around the buffer edges.
+    pub fn draw_box(&mut self, x: i32, y: i32, w: i32, h: i32, frame: &BoxFrame, fill: BoxFill) -> &mut Self {
+        for j in 0..h {
+            for i in 0..w {
+                let achar = match (frame.achar_at(i, j, w, h), fill) {
+                    (Some(achar), _) => achar,
+                    (None, BoxFill::Keep) => continue,
+                    (None, BoxFill::Fill) => frame.fill,
+                    (None, BoxFill::Clear) => AChar::default(),
+                };
+                let idx = self._wrapped_idx(x + i, y + j);
+                self.resources.set_achar(idx, &achar);
+            }
+        }
+        self
+    }
+
+    pub fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, achar: &AChar) -> &mut Self {
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let (mut x, mut y) = (x0, y0);
+        let mut err = dx + dy;
+        loop {
+            let idx = self._wrapped_idx(x, y);
+            self.resources.set_achar(idx, achar);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = err * 2;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+        self
+    }
+
+    pub fn draw_rect(&mut self, x: i32, y: i32, w: i32, h: i32, achar: &AChar) -> &mut Self {
+        if w <= 0 || h <= 0 {
+            return self;
+        }
+        self.draw_line(x, y, x + w - 1, y, achar);
+        self.draw_line(x, y + h - 1, x + w - 1, y + h - 1, achar);
+        self.draw_line(x, y, x, y + h - 1, achar);
+        self.draw_line(x + w - 1, y, x + w - 1, y + h - 1, achar);
+        self
+    }
+
+    // Fills the 4-connected cells that match the cell at (x, y). With wrap
+    // the region continues across the buffer edges.
+    pub fn flood_fill(&mut self, x: i32, y: i32, achar: &AChar, flood_match: FloodMatch, wrap: bool) -> &mut Self {
+        let (width, height) = self.resources.rect_size();
+        let (x, y) = (u_mod(x, width), u_mod(y, height));
+        let seed = self.resources.get_achar_at(x, y);
+        let mut visited = vec![false; (width * height) as usize];
+        let mut stack = vec![(x, y)];
+        visited[(x + y * width) as usize] = true;
+        while let Some((cx, cy)) = stack.pop() {
+            self.resources.set_achar_at(cx, cy, achar);
+            for (nx, ny) in [(cx - 1, cy), (cx + 1, cy), (cx, cy - 1), (cx, cy + 1)] {
+                let (nx, ny) = if wrap {
+                    (u_mod(nx, width), u_mod(ny, height))
+                } else if nx < 0 || nx >= width || ny < 0 || ny >= height {
+                    continue;
+                } else {
+                    (nx, ny)
+                };
+                let idx = (nx + ny * width) as usize;
+                if !visited[idx] && flood_match.matches(&self.resources.get_achar_at(nx, ny), &seed) {
+                    visited[idx] = true;
+                    stack.push((nx, ny));
+                }
+            }
+        }
+        self
+    }
+
+    // The top-left cell of the (transformed) block goes to (x, y).
+    pub fn stamp_metatile(&mut self, x: i32, y: i32, metatile: &Metatile, symmetry: BgSymmetry) -> &mut Self {
+        let metatile = metatile.transformed(symmetry);
+        self.resources.set_rect(x, y, metatile.width(), metatile.height(), &metatile.achars);
+        self
+    }
+
+    pub fn stamp_metatile_from(&mut self, x: i32, y: i32, table: &MetatileTable, index: usize, symmetry: BgSymmetry) -> &mut Self {
+        if let Some(metatile) = table.get(index) {
+            self.stamp_metatile(x, y, metatile, symmetry);
+        }
+        self
+    }
+
+    // The first metatile (and the symmetry it was stamped with) whose cells
+    // equal the region from (x, y). Normal is tried first for each entry.
+    pub fn match_metatile(&self, x: i32, y: i32, table: &MetatileTable) -> Option<(usize, BgSymmetry)> {
+        for (index, metatile) in table.iter().enumerate() {
+            for n in 0..8 {
+                let symmetry = BgSymmetry::from(n);
+                let transformed = metatile.transformed(symmetry);
+                if self.get_rect(x, y, transformed.width(), transformed.height()) == transformed.achars {
+                    return Some((index, symmetry));
+                }
+            }
+        }
+        None
+    }
+
     pub fn rendering(&mut self) -> i32 {
+        let done = self.resources.rendering();
+        self.pixel_scale = self.resources.pixel_scale();
         let scale = self.pixel_scale;
         let mut draw_rects: Vec<([f64; 4], [f64; 4])> = Vec::with_capacity(4);
-        let (x0, y0) = (u_mod(self.view_pos.0, self.whole_size.0 as i32), u_mod(self.view_pos.1, self.whole_size.1 as i32));
+        let (x0, y0) = (u_mod(self.view_pos.0, self.whole_size.0), u_mod(self.view_pos.1, self.whole_size.1));
         let (x_end, y_end) = (x0 + self.view_size.0, y0 + self.view_size.1);
         let (w0, y1, w1) = if x_end > self.whole_size.0 {
             (self.whole_size.0 - x0, y0, x_end - self.whole_size.0)
@@ -447,15 +1123,152 @@ impl <'a> BgPlane<'a> {
                 [0.0,                 0.0,                 (w1 * scale) as f64, (h2 * scale) as f64],
             ));
         }
+        let symmetry = self.screen_symmetry();
+        if symmetry != BgSymmetry::Normal {
+            let view = (self.view_size.0 * scale, self.view_size.1 * scale);
+            let whole = (self.whole_size.0 * scale, self.whole_size.1 * scale);
+            let transform = |rect: [f64; 4], size| {
+                let r = symmetry.transform_rect([rect[0] as i32, rect[1] as i32, rect[2] as i32, rect[3] as i32], size);
+                [r[0] as f64, r[1] as f64, r[2] as f64, r[3] as f64]
+            };
+            for (dst, src) in draw_rects.iter_mut() {
+                *dst = transform(*dst, view);
+                *src = transform(*src, whole);
+            }
+        }
         self.draw_rects = draw_rects;
-        self.resources.rendering()
+        done
     }
 
     pub fn whole_image(&self) -> &RgbaImage {
         self.resources.rendered_image()
     }
 
+    pub fn whole_high_image(&self) -> Option<&RgbaImage> {
+        self.resources.rendered_high_image()
+    }
+
+    // Pixel rects of whole_image() changed by the last rendering().
+    pub fn dirty_rects(&self) -> &[[u32; 4]] {
+        self.resources.dirty_rects()
+    }
+
     pub fn draw_rects(&self) -> &DrawRects {
         &self.draw_rects
     }
-}
\ No newline at end of file
+
+    // The cell under the pixel (px, py) of the composed view (pixel_scale
+    // applied, rotated with SymmetryScope::Screen), and the position in the
+    // cell in unscaled, unrotated pixels. None outside the view.
+    pub fn screen_to_cell(&self, px: i32, py: i32) -> Option<((i32, i32), (i32, i32))> {
+        let symmetry = self.screen_symmetry();
+        let screen_view = symmetry.transform_size(self.view_size);
+        let (sx, sy) = (px.div_euclid(self.pixel_scale), py.div_euclid(self.pixel_scale));
+        if sx < 0 || sx >= screen_view.0 || sy < 0 || sy >= screen_view.1 {
+            return None;
+        }
+        let (vx, vy) = symmetry.inverse().transform_pos((sx, sy), screen_view);
+        let x = u_mod(self.view_pos.0 + vx, self.whole_size.0);
+        let y = u_mod(self.view_pos.1 + vy, self.whole_size.1);
+        let cell = PATTERN_SIZE as i32;
+        Some(((x / cell, y / cell), (x % cell, y % cell)))
+    }
+
+    // Where the cell (x, y) is shown in the composed view, as of the last
+    // rendering(). A cell on the wrap seam of the view is split into
+    // several rects, a cell out of the view gives none.
+    pub fn cell_to_screen(&self, x: i32, y: i32) -> Vec<[f64; 4]> {
+        let scale = self.pixel_scale;
+        let cell = PATTERN_SIZE as i32 * scale;
+        let (x, y) = (u_mod(x, self.buffer_rect_size.0), u_mod(y, self.buffer_rect_size.1));
+        let whole = (self.whole_size.0 * scale, self.whole_size.1 * scale);
+        let r = self.screen_symmetry().transform_rect([x * cell, y * cell, cell, cell], whole);
+        let r = [r[0] as f64, r[1] as f64, r[2] as f64, r[3] as f64];
+        let mut rects = Vec::new();
+        for (dst, src) in &self.draw_rects {
+            let (x0, y0) = (r[0].max(src[0]), r[1].max(src[1]));
+            let (x1, y1) = ((r[0] + r[2]).min(src[0] + src[2]), (r[1] + r[3]).min(src[1] + src[3]));
+            if x0 < x1 && y0 < y1 {
+                rects.push([x0 - src[0] + dst[0], y0 - src[1] + dst[1], x1 - x0, y1 - y0]);
+            }
+        }
+        rects
+    }
+
+    // draw_rects cut down to the parts shown through the window.
+    pub fn windowed_draw_rects(&self) -> DrawRects {
+        let window = match &self.window {
+            Some(window) => window,
+            None => return self.draw_rects.clone(),
+        };
+        let scale = self.pixel_scale as f64;
+        let symmetry = self.screen_symmetry();
+        let screen_view = symmetry.transform_size(self.view_size);
+        let mut draw_rects: DrawRects = Vec::new();
+        for (dst, src) in &self.draw_rects {
+            let clip = [
+                (dst[0] / scale) as i32, (dst[1] / scale) as i32,
+                (dst[2] / scale) as i32, (dst[3] / scale) as i32,
+            ];
+            // the window is given in the unrotated view
+            let unrotated_clip = symmetry.inverse().transform_rect(clip, screen_view);
+            for r in window.visible_rects(unrotated_clip) {
+                let r = symmetry.transform_rect(r, self.view_size);
+                let (ox, oy) = ((r[0] - clip[0]) as f64 * scale, (r[1] - clip[1]) as f64 * scale);
+                let (w, h) = (r[2] as f64 * scale, r[3] as f64 * scale);
+                draw_rects.push((
+                    [dst[0] + ox, dst[1] + oy, w, h],
+                    [src[0] + ox, src[1] + oy, w, h],
+                ));
+            }
+        }
+        draw_rects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::texture_bank::test_texture_bank;
+
+    #[test]
+    fn markup_error_writes_nothing() {
+        let mut texture_bank = test_texture_bank(vec![None; 0x80]);
+        let mut bg = BgPlane::new((8, 4), (64, 32), Rc::new(RefCell::new(&mut texture_bank)));
+        bg.set_cur_pos(1, 1);
+        let error = bg.put_markup("ok{p:1}text{q}", None).err().unwrap();
+        assert_eq!(error.kind, MarkupErrorKind::UnknownTag("q".to_string()));
+        assert!(bg.get_rect(0, 0, 8, 4).iter().all(|achar| *achar == AChar::default()));
+        assert_eq!(bg.cur_idx(), 9);
+        bg.put_markup("ok", None).unwrap();
+        assert_eq!(bg.get_code_at(2, 1), 'k' as BgCode);
+    }
+
+    #[test]
+    fn high_image_cleared_without_texture() {
+        const SOLID: [u64; 8] = [0x0101_0101_0101_0101; 8];
+        let mut pattern_tbl = vec![None; 3];
+        pattern_tbl[1] = Some((1, 1, &SOLID[..]));
+        let mut texture_bank = test_texture_bank(pattern_tbl);
+        let mut bg = BgPlane::new((4, 4), (32, 32), Rc::new(RefCell::new(&mut texture_bank)));
+        bg.set_code_at(1, 1, 1u32).set_priority_at(1, 1, true);
+        bg.rendering();
+        assert_eq!(bg.whole_high_image().unwrap().get_pixel(8, 8)[3], 255);
+        // a pattern without a texture: nothing is drawn and nothing is left
+        bg.set_code_at(1, 1, 2u32);
+        bg.rendering();
+        assert_eq!(bg.whole_high_image().unwrap().get_pixel(8, 8)[3], 0);
+    }
+
+    #[test]
+    fn streamed_cells_not_in_history() {
+        let mut texture_bank = test_texture_bank(vec![None; 0x80]);
+        let mut bg = BgPlane::new((8, 8), (32, 32), Rc::new(RefCell::new(&mut texture_bank)));
+        bg.enable_history(4);
+        let source = |x: i32, y: i32| AChar::new(u_mod(x + y, 0x80) as u32, 0, BgSymmetry::Normal);
+        bg.set_map_source(Rc::new(source), 1);
+        bg.set_view_pos(40, 24);
+        assert!(!bg.can_undo());
+        assert_eq!(bg.get_code_at(5, 3), 8);
+    }
+}
@@ -4,7 +4,9 @@ pub use std::cell::RefCell;
 pub use super::bgsp_common::{
     PATTERN_SIZE, NUM_PALETTE_COL, PIXEL_SCALE_MAX,
     Rgba, RgbaImage, imageops,
-    BgCode, BgPalette, BgSymmetry
+    BgCode, BgPalette, BgSymmetry, BgPriority,
+    BlendMode, ClipWindow, WindowRegion, WindowMode, BRIGHTNESS_MAX,
+    SymmetryScope,
 };
 use super::texture_bank;
 pub type BgTextureBank<'a> = texture_bank::TextureBank<'a>;
@@ -20,14 +22,21 @@ fn u_mod(x: i32, p: i32) -> i32 {
 pub struct CharAttributes {
     pub palette: BgPalette,
     pub symmetry: BgSymmetry,
+    pub priority: BgPriority,
 }
 impl CharAttributes {
     pub fn new(palette: BgPalette, symmetry: BgSymmetry) -> Self {
         Self {
             palette,
-            symmetry
+            symmetry,
+            priority: false,
         }
     }
+
+    pub fn with_priority(mut self, priority: BgPriority) -> Self {
+        self.priority = priority;
+        self
+    }
 }
 
 #[derive(Default, Clone, Copy, PartialEq, Eq)]
@@ -35,26 +44,61 @@ pub struct AChar {
     pub code: BgCode,
     pub palette: BgPalette,
     pub symmetry: BgSymmetry,
+    pub priority: BgPriority,
 }
 impl AChar {
     pub fn new<T: Into<BgCode>>(code: T, palette: BgPalette, symmetry: BgSymmetry) -> Self {
         Self {
             code: code.into(),
             palette,
-            symmetry
+            symmetry,
+            priority: false,
         }
     }
 
+    pub fn with_priority(mut self, priority: BgPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     #[inline]
     pub fn force_dirty() -> Self {
         Self {
-            code: DIRTY_MARK.into(),
-            palette: DIRTY_MARK.into(),
+            code: DIRTY_MARK,
+            palette: DIRTY_MARK,
             symmetry: BgSymmetry::non_default(),
+            priority: true,
         }
     }
 }
 
+#[derive(Clone)]
+pub struct BgSnapshot {
+    rect_size: (i32, i32),
+    buffer: Vec<AChar>,
+}
+impl BgSnapshot {
+    pub const fn rect_size(&self) -> (i32, i32) {
+        self.rect_size
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CellChange {
+    idx: usize,
+    before: AChar,
+    after: AChar,
+}
+
+// Cell level journal of the buffer. The changes since the last
+// commit_undo_step form the next undo step.
+struct History {
+    limit: usize,
+    pending: Vec<CellChange>,
+    undo_steps: Vec<Vec<CellChange>>,
+    redo_steps: Vec<Vec<CellChange>>,
+}
+
 pub struct BgResources<'a> {
     rect_size: (i32, i32),
     linear_size: i32,
@@ -63,11 +107,17 @@ pub struct BgResources<'a> {
     texture_bank: Rc<RefCell<&'a mut BgTextureBank<'a>>>,
     pixel_scale: i32,
     base_symmetry: BgSymmetry,
+    symmetry_scope: SymmetryScope,
     rendered_image: RgbaImage,
+    rendered_high_image: Option<RgbaImage>,
+    history: Option<History>,
+    dirty_rects: Vec<[u32; 4]>,
+    texture_generation: u32,
 }
 
 const WIDTH_MAX: i32 = 8192;    // = 32 * 256 Characters
 const HEIGHT_MAX: i32 = 8192;   // = 32 * 256 Characters
+const DIRTY_RECTS_MAX: usize = 32; // more are reported as their bounding box
 
 impl<'a> BgResources<'a> {
 
@@ -88,6 +138,7 @@ impl<'a> BgResources<'a> {
         let cur_buffer = vec![AChar::default(); linear_size as usize];
         let alt_buffer = vec![AChar::force_dirty(); linear_size as usize];
         let pixel_scale = texture_bank.borrow().pixel_scale();
+        let texture_generation = texture_bank.borrow().generation();
         let rendered_image =
             RgbaImage::new(
                 (width * PATTERN_SIZE as i32 * pixel_scale) as u32,
@@ -102,7 +153,12 @@ impl<'a> BgResources<'a> {
             texture_bank,
             pixel_scale,
             base_symmetry,
+            symmetry_scope: SymmetryScope::default(),
             rendered_image,
+            rendered_high_image: None,
+            history: None,
+            dirty_rects: Vec::new(),
+            texture_generation,
         }
     }
 
@@ -139,10 +195,99 @@ impl<'a> BgResources<'a> {
     }
 
     pub fn set_base_symmetry(&mut self, base_symmetry: BgSymmetry) -> &mut Self {
-        self.base_symmetry = base_symmetry;
+        if self.base_symmetry != base_symmetry {
+            self.base_symmetry = base_symmetry;
+            self._reallocate_images();
+            self.invalidate_all();
+        }
+        self
+    }
+
+    pub const fn symmetry_scope(&self) -> SymmetryScope {
+        self.symmetry_scope
+    }
+
+    pub fn set_symmetry_scope(&mut self, symmetry_scope: SymmetryScope) -> &mut Self {
+        if self.symmetry_scope != symmetry_scope {
+            self.symmetry_scope = symmetry_scope;
+            self._reallocate_images();
+            self.invalidate_all();
+        }
         self
     }
 
+    // The symmetry of the whole rendered image, Normal unless the scope is Screen.
+    pub fn screen_symmetry(&self) -> BgSymmetry {
+        match self.symmetry_scope {
+            SymmetryScope::Texture => BgSymmetry::Normal,
+            SymmetryScope::Screen => self.base_symmetry,
+        }
+    }
+
+    fn _texture_symmetry(&self, symmetry: BgSymmetry) -> BgSymmetry {
+        match self.symmetry_scope {
+            SymmetryScope::Texture => symmetry.compose(self.base_symmetry),
+            SymmetryScope::Screen => symmetry.followed_by(self.base_symmetry),
+        }
+    }
+
+    // Resizes the rendered images for the pixel scale and screen symmetry.
+    fn _reallocate_images(&mut self) {
+        let cell_size = PATTERN_SIZE as i32 * self.pixel_scale;
+        let (w, h) = self.screen_symmetry().transform_size(self.rect_size);
+        let size = ((w * cell_size) as u32, (h * cell_size) as u32);
+        if self.rendered_image.dimensions() != size {
+            self.rendered_image = RgbaImage::new(size.0, size.1);
+            self.rendered_high_image = None;
+        }
+    }
+
+    // Makes rendering() redraw the cells even though they are unchanged.
+    pub fn invalidate_all(&mut self) -> &mut Self {
+        self.alt_buffer.fill(AChar::force_dirty());
+        self
+    }
+
+    pub fn invalidate_rect(&mut self, x: i32, y: i32, w: i32, h: i32) -> &mut Self {
+        for j in 0..h.min(self.rect_size.1) {
+            for i in 0..w.min(self.rect_size.0) {
+                let idx = self._wrapped_idx(x + i, y + j) as usize;
+                self.alt_buffer[idx] = AChar::force_dirty();
+            }
+        }
+        self
+    }
+
+    // Follows the changes of the TextureBank (tables, pixel scale).
+    fn _sync_texture_bank(&mut self) {
+        let (generation, pixel_scale) = {
+            let texture_bank = self.texture_bank.borrow();
+            (texture_bank.generation(), texture_bank.pixel_scale())
+        };
+        if generation == self.texture_generation {
+            return;
+        }
+        self.texture_generation = generation;
+        self.pixel_scale = pixel_scale;
+        self._reallocate_images();
+        self.invalidate_all();
+    }
+
+    #[inline(always)]
+    fn _write(&mut self, idx: usize, achar: AChar) {
+        let before = self.cur_buffer[idx];
+        if before == achar {
+            return;
+        }
+        if let Some(history) = &mut self.history {
+            if history.pending.is_empty() {
+                history.redo_steps.clear();
+            }
+            history.pending.push(CellChange { idx, before, after: achar });
+        }
+        self.cur_buffer[idx] = achar;
+    }
+
     #[inline(always)]
     fn _get_achar(&self, idx: i32) -> AChar {
         let idx = u_mod(idx, self.linear_size) as usize;
@@ -152,10 +297,18 @@ impl<'a> BgResources<'a> {
     #[inline(always)]
     fn _set_achar(&mut self, idx: i32, achar: &AChar) -> &mut Self {
         let idx = u_mod(idx, self.linear_size) as usize;
-        self.cur_buffer[idx] = *achar;
+        self._write(idx, *achar);
         self
     }
 
+    // Not journaled: the cells streamed from a MapSource are no edits to
+    // undo. An undo step of an edit in a cell streamed over since restores
+    // the edit's before value there all the same.
+    pub(crate) fn load_achar(&mut self, idx: i32, achar: &AChar) {
+        let idx = u_mod(idx, self.linear_size) as usize;
+        self.cur_buffer[idx] = *achar;
+    }
+
     pub fn get_achar(&self, idx: i32) -> AChar {
         self._get_achar(idx)
     }
@@ -207,14 +360,20 @@ impl<'a> BgResources<'a> {
         CharAttributes {
             palette: self.cur_buffer[idx].palette,
             symmetry: self.cur_buffer[idx].symmetry,
+            priority: self.cur_buffer[idx].priority,
         }
     }
 
     #[inline(always)]
     fn _set_attributes(&mut self, idx: i32, attributes: &CharAttributes) -> &mut Self {
         let idx = u_mod(idx, self.linear_size) as usize;
-        self.cur_buffer[idx].palette = attributes.palette;
-        self.cur_buffer[idx].symmetry = attributes.symmetry;
+        let achar = AChar {
+            palette: attributes.palette,
+            symmetry: attributes.symmetry,
+            priority: attributes.priority,
+            ..self.cur_buffer[idx]
+        };
+        self._write(idx, achar);
         self
     }
 
@@ -267,7 +426,8 @@ impl<'a> BgResources<'a> {
     #[inline(always)]
     fn _set_code(&mut self, idx: i32, code: BgCode) -> &mut Self {
         let idx = u_mod(idx, self.linear_size) as usize;
-        self.cur_buffer[idx].code = code;
+        let achar = AChar { code, ..self.cur_buffer[idx] };
+        self._write(idx, achar);
         self
     }
 
@@ -323,7 +483,8 @@ impl<'a> BgResources<'a> {
     #[inline(always)]
     fn _set_palette(&mut self, idx: i32, palette: BgPalette) -> &mut Self {
         let idx = u_mod(idx, self.linear_size) as usize;
-        self.cur_buffer[idx].palette = palette;
+        let achar = AChar { palette, ..self.cur_buffer[idx] };
+        self._write(idx, achar);
         self
     }
 
@@ -376,7 +537,8 @@ impl<'a> BgResources<'a> {
     #[inline(always)]
     fn _set_symmetry(&mut self, idx: i32, symmetry: BgSymmetry) -> &mut Self {
         let idx = u_mod(idx ,self.linear_size) as usize;
-        self.cur_buffer[idx].symmetry = symmetry;
+        let achar = AChar { symmetry, ..self.cur_buffer[idx] };
+        self._write(idx, achar);
         self
     }
 
@@ -420,35 +582,372 @@ impl<'a> BgResources<'a> {
         self
     }
 
+    #[inline(always)]
+    fn _get_priority(&self, idx: i32) -> BgPriority {
+        let idx = u_mod(idx, self.linear_size) as usize;
+        self.cur_buffer[idx].priority
+    }
+
+    #[inline(always)]
+    fn _set_priority(&mut self, idx: i32, priority: BgPriority) -> &mut Self {
+        let idx = u_mod(idx, self.linear_size) as usize;
+        let achar = AChar { priority, ..self.cur_buffer[idx] };
+        self._write(idx, achar);
+        self
+    }
+
+    pub fn get_priority(&self, idx: i32) -> BgPriority {
+        self._get_priority(idx)
+    }
+
+    pub fn get_priority_at(&self, x: i32, y: i32) -> BgPriority {
+        let idx = x + y * self.rect_size.0;
+        self._get_priority(idx)
+    }
+
+    pub fn set_priority(&mut self, idx: i32, priority: BgPriority) -> &mut Self {
+        self._set_priority(idx, priority)
+    }
+
+    pub fn set_priority_n(&mut self, idx: i32, priority: BgPriority, n: i32) -> &mut Self {
+        for i in 0..n {
+            self._set_priority(idx + i, priority);
+        }
+        self
+    }
+
+    pub fn set_priority_at(&mut self, x: i32, y: i32, priority: BgPriority) -> &mut Self {
+        let idx = x + y * self.rect_size.0;
+        self._set_priority(idx, priority)
+    }
+
+    pub fn set_priority_n_at(&mut self, x: i32, y: i32, priority: BgPriority, n: i32) -> &mut Self {
+        let idx = x + y * self.rect_size.0;
+        for i in 0..n {
+            self._set_priority(idx + i, priority);
+        }
+        self
+    }
+
+    pub fn fill_priority(&mut self, priority: BgPriority) -> &mut Self {
+        for idx in 0..self.linear_size {
+            self._set_priority(idx, priority);
+        }
+        self
+    }
+
+    // The rect APIs address the buffer as a torus, x and y wrap separately.
+    #[inline(always)]
+    fn _wrapped_idx(&self, x: i32, y: i32) -> i32 {
+        u_mod(x, self.rect_size.0) + u_mod(y, self.rect_size.1) * self.rect_size.0
+    }
+
+    // Row major w x h AChars from (x, y).
+    pub fn get_rect(&self, x: i32, y: i32, w: i32, h: i32) -> Vec<AChar> {
+        let mut achars = Vec::with_capacity((w.max(0) * h.max(0)) as usize);
+        for j in 0..h {
+            for i in 0..w {
+                achars.push(self._get_achar(self._wrapped_idx(x + i, y + j)));
+            }
+        }
+        achars
+    }
+
+    pub fn set_rect(&mut self, x: i32, y: i32, w: i32, h: i32, achars: &[AChar]) -> &mut Self {
+        for j in 0..h {
+            for i in 0..w {
+                if let Some(achar) = achars.get((i + j * w) as usize) {
+                    let idx = self._wrapped_idx(x + i, y + j);
+                    self._set_achar(idx, achar);
+                }
+            }
+        }
+        self
+    }
+
+    // Safe for overlapping source and destination.
+    pub fn copy_rect(&mut self, src_pos: (i32, i32), size: (i32, i32), dst_pos: (i32, i32)) -> &mut Self {
+        let achars = self.get_rect(src_pos.0, src_pos.1, size.0, size.1);
+        self.set_rect(dst_pos.0, dst_pos.1, size.0, size.1, &achars)
+    }
+
+    pub fn copy_rect_from(&mut self, src: &BgResources, src_pos: (i32, i32), size: (i32, i32), dst_pos: (i32, i32)) -> &mut Self {
+        let achars = src.get_rect(src_pos.0, src_pos.1, size.0, size.1);
+        self.set_rect(dst_pos.0, dst_pos.1, size.0, size.1, &achars)
+    }
+
+    // Moves the whole contents by (dx, dy). The cells moved out come in from
+    // the other side when op_fill is None, otherwise the vacated cells are filled.
+    pub fn shift(&mut self, dx: i32, dy: i32, op_fill: Option<&AChar>) -> &mut Self {
+        let (w, h) = self.rect_size;
+        let achars = self.get_rect(0, 0, w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let (sx, sy) = (x - dx, y - dy);
+                let achar = match op_fill {
+                    Some(fill) if sx < 0 || sx >= w || sy < 0 || sy >= h => *fill,
+                    _ => achars[(u_mod(sx, w) + u_mod(sy, h) * w) as usize],
+                };
+                self._set_achar(x + y * w, &achar);
+            }
+        }
+        self
+    }
+
+    pub fn fill_achar_rect(&mut self, x: i32, y: i32, w: i32, h: i32, achar: &AChar) -> &mut Self {
+        for j in 0..h {
+            for i in 0..w {
+                let idx = self._wrapped_idx(x + i, y + j);
+                self._set_achar(idx, achar);
+            }
+        }
+        self
+    }
+
+    pub fn fill_attributes_rect(&mut self, x: i32, y: i32, w: i32, h: i32, attributes: &CharAttributes) -> &mut Self {
+        for j in 0..h {
+            for i in 0..w {
+                let idx = self._wrapped_idx(x + i, y + j);
+                self._set_attributes(idx, attributes);
+            }
+        }
+        self
+    }
+
+    pub fn fill_code_rect<T: Into<BgCode>>(&mut self, x: i32, y: i32, w: i32, h: i32, code: T) -> &mut Self {
+        let code = code.into();
+        for j in 0..h {
+            for i in 0..w {
+                let idx = self._wrapped_idx(x + i, y + j);
+                self._set_code(idx, code);
+            }
+        }
+        self
+    }
+
+    pub fn clear_rect(&mut self, x: i32, y: i32, w: i32, h: i32) -> &mut Self {
+        let achar: AChar = Default::default();
+        self.fill_achar_rect(x, y, w, h, &achar)
+    }
+
+    pub fn snapshot(&self) -> BgSnapshot {
+        BgSnapshot {
+            rect_size: self.rect_size,
+            buffer: self.cur_buffer.clone(),
+        }
+    }
+
+    // Restores the area both sizes have in common, journaled as any other
+    // change. The restored cells are drawn again by the next rendering().
+    pub fn restore(&mut self, snapshot: &BgSnapshot) -> &mut Self {
+        let w = self.rect_size.0.min(snapshot.rect_size.0);
+        let h = self.rect_size.1.min(snapshot.rect_size.1);
+        for y in 0..h {
+            for x in 0..w {
+                let idx = (x + y * self.rect_size.0) as usize;
+                self._write(idx, snapshot.buffer[(x + y * snapshot.rect_size.0) as usize]);
+                self.alt_buffer[idx] = AChar::force_dirty();
+            }
+        }
+        self
+    }
+
+    // Keeps up to limit undo steps of the changes made from now on.
+    pub fn enable_history(&mut self, limit: usize) -> &mut Self {
+        match &mut self.history {
+            Some(history) => history.limit = limit,
+            None => self.history = Some(History {
+                limit,
+                pending: Vec::new(),
+                undo_steps: Vec::new(),
+                redo_steps: Vec::new(),
+            }),
+        }
+        self
+    }
+
+    pub fn disable_history(&mut self) -> &mut Self {
+        self.history = None;
+        self
+    }
+
+    pub fn clear_history(&mut self) -> &mut Self {
+        if let Some(history) = &mut self.history {
+            history.pending.clear();
+            history.undo_steps.clear();
+            history.redo_steps.clear();
+        }
+        self
+    }
+
+    pub fn commit_undo_step(&mut self) -> &mut Self {
+        if let Some(history) = &mut self.history {
+            if !history.pending.is_empty() {
+                let step = std::mem::take(&mut history.pending);
+                history.undo_steps.push(step);
+                if history.undo_steps.len() > history.limit {
+                    let excess = history.undo_steps.len() - history.limit;
+                    history.undo_steps.drain(..excess);
+                }
+            }
+        }
+        self
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.history.as_ref().is_some_and(|history| !history.pending.is_empty() || !history.undo_steps.is_empty())
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.history.as_ref().is_some_and(|history| history.pending.is_empty() && !history.redo_steps.is_empty())
+    }
+
+    fn _apply_step(&mut self, step: &[CellChange], undo: bool) {
+        if undo {
+            for change in step.iter().rev() {
+                self.cur_buffer[change.idx] = change.before;
+                self.alt_buffer[change.idx] = AChar::force_dirty();
+            }
+        } else {
+            for change in step {
+                self.cur_buffer[change.idx] = change.after;
+                self.alt_buffer[change.idx] = AChar::force_dirty();
+            }
+        }
+    }
+
+    // Uncommitted changes are committed as a step first.
+    pub fn undo(&mut self) -> bool {
+        self.commit_undo_step();
+        let step = match self.history.as_mut().and_then(|history| history.undo_steps.pop()) {
+            Some(step) => step,
+            None => return false,
+        };
+        self._apply_step(&step, true);
+        if let Some(history) = &mut self.history {
+            history.redo_steps.push(step);
+        }
+        true
+    }
+
+    pub fn redo(&mut self) -> bool {
+        if !self.can_redo() {
+            return false;
+        }
+        let step = match self.history.as_mut().and_then(|history| history.redo_steps.pop()) {
+            Some(step) => step,
+            None => return false,
+        };
+        self._apply_step(&step, false);
+        if let Some(history) = &mut self.history {
+            history.undo_steps.push(step);
+        }
+        true
+    }
+
     pub fn rendering(&mut self) -> i32 {
+        self._sync_texture_bank();
         let mut done = 0;
         let mut idx = 0;
+        let screen_symmetry = self.screen_symmetry();
+        // changed cells as x, y, w, h, closed ones and the ones reaching
+        // the previous row
+        let mut cell_rects: Vec<[i32; 4]> = Vec::new();
+        let mut open_rects: Vec<[i32; 4]> = Vec::new();
         for y in 0..self.rect_size.1 {
+            let mut row_runs: Vec<(i32, i32)> = Vec::new();
             for x in 0..self.rect_size.0 {
                 if self.cur_buffer[idx] != self.alt_buffer[idx] {
                     self.alt_buffer[idx] = self.cur_buffer[idx];
                     // rendering proc
+                    let cell_size = PATTERN_SIZE as i32 * self.pixel_scale;
+                    let (tx, ty) = screen_symmetry.transform_pos((x, y), self.rect_size);
+                    let (px, py) = (tx * cell_size, ty * cell_size);
+                    // the high image is cleared first, a priority cell without
+                    // a texture must not leave its old pixels there
+                    if let Some(high_image) = &mut self.rendered_high_image {
+                        for sy in py..py + cell_size {
+                            for sx in px..px + cell_size {
+                                high_image.put_pixel(sx as u32, sy as u32, Rgba([0, 0, 0, 0]));
+                            }
+                        }
+                    }
                     if let Some(t) = self.texture_bank.borrow_mut().texture(
                         self.cur_buffer[idx].code,
                         self.cur_buffer[idx].palette,
-                        self.cur_buffer[idx].symmetry.compose(self.base_symmetry),
+                        self._texture_symmetry(self.cur_buffer[idx].symmetry),
                     ) {
-                        imageops::replace(
-                            &mut self.rendered_image,
-                            &*t,
-                            (x * PATTERN_SIZE as i32 * self.pixel_scale) as i64,
-                            (y * PATTERN_SIZE as i32 * self.pixel_scale) as i64,
-                        );
+                        imageops::replace(&mut self.rendered_image, &*t, px as i64, py as i64);
+                        if self.cur_buffer[idx].priority {
+                            let (w, h) = self.rendered_image.dimensions();
+                            let high_image = self.rendered_high_image.get_or_insert_with(|| RgbaImage::new(w, h));
+                            imageops::replace(high_image, &*t, px as i64, py as i64);
+                        }
+                    }
+                    match row_runs.last_mut() {
+                        Some(run) if run.1 == x => run.1 = x + 1,
+                        _ => row_runs.push((x, x + 1)),
                     }
                     done += 1;
                 }
                 idx += 1;
             }
+            let mut next_rects = Vec::with_capacity(row_runs.len());
+            for (l, r) in row_runs {
+                match open_rects.iter().position(|rect| rect[0] == l && rect[2] == r - l) {
+                    Some(n) => {
+                        let mut rect = open_rects.swap_remove(n);
+                        rect[3] += 1;
+                        next_rects.push(rect);
+                    }
+                    None => next_rects.push([l, y, r - l, 1]),
+                }
+            }
+            cell_rects.append(&mut open_rects);
+            open_rects = next_rects;
         }
+        cell_rects.append(&mut open_rects);
+        self._set_dirty_rects(cell_rects);
         done
     }
 
+    fn _set_dirty_rects(&mut self, cell_rects: Vec<[i32; 4]>) {
+        let screen_symmetry = self.screen_symmetry();
+        let cell_rects: Vec<[i32; 4]> = cell_rects.into_iter()
+            .map(|r| screen_symmetry.transform_rect(r, self.rect_size))
+            .collect();
+        let cell_rects = if cell_rects.len() > DIRTY_RECTS_MAX {
+            let bounds = cell_rects.iter().fold([i32::MAX, i32::MAX, i32::MIN, i32::MIN], |b, r| {
+                [b[0].min(r[0]), b[1].min(r[1]), b[2].max(r[0] + r[2]), b[3].max(r[1] + r[3])]
+            });
+            vec![[bounds[0], bounds[1], bounds[2] - bounds[0], bounds[3] - bounds[1]]]
+        } else {
+            cell_rects
+        };
+        let cell_size = PATTERN_SIZE as i32 * self.pixel_scale;
+        self.dirty_rects = cell_rects.iter()
+            .map(|r| [
+                (r[0] * cell_size) as u32,
+                (r[1] * cell_size) as u32,
+                (r[2] * cell_size) as u32,
+                (r[3] * cell_size) as u32,
+            ])
+            .collect();
+    }
+
+    // Pixel rects (x, y, w, h) of rendered_image redrawn by the last
+    // rendering(), for partial texture uploads.
+    pub fn dirty_rects(&self) -> &[[u32; 4]] {
+        &self.dirty_rects
+    }
+
     pub fn rendered_image(&self) -> &RgbaImage {
         &self.rendered_image
     }
+
+    // Only the cells with the priority bit set, the others are transparent.
+    // None until the first such cell has been rendered.
+    pub fn rendered_high_image(&self) -> Option<&RgbaImage> {
+        self.rendered_high_image.as_ref()
+    }
 }
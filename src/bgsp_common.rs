@@ -31,6 +31,7 @@ pub type Palette = u32;
 pub type BgCode = Code;
 pub type BgPalette = Palette;
 pub type BgSymmetry = Symmetry;
+pub type BgPriority = bool;
 
 pub type SpCode = Code;
 pub type SpPalette = Palette;
@@ -44,8 +45,9 @@ const ROTATE_90_FLIP_H: isize  = ROTATE_90 | FLIP_H;
 const ROTATE_90_FLIP_V: isize  = ROTATE_90 | FLIP_V;
 const ROTATE_90_FLIP_HV: isize = ROTATE_90 | FLIP_HV;
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
 pub enum Symmetry {
+    #[default]
     Normal         = 0b000,
     FlipH          = FLIP_H,
     FlipV          = FLIP_V,
@@ -56,10 +58,6 @@ pub enum Symmetry {
     Rotate90FlipHV = ROTATE_90_FLIP_HV,  // Rotate270
 }
 
-impl Default for Symmetry {
-    fn default() -> Self { Self::Normal }
-}
-
 impl From<isize> for Symmetry {
     fn from(n: isize) -> Self {
         match n & ROTATE_90_FLIP_HV {
@@ -125,6 +123,260 @@ impl Symmetry {
     pub fn has_rotate90(&self) -> bool {
         *self as isize & ROTATE_90 != 0
     }
+
+    // The symmetry equal to applying self first and then s, e.g. for a
+    // cell drawn with self inside a block drawn with s.
+    #[inline]
+    pub fn followed_by(&self, s: Self) -> Self {
+        let (a, b) = (*self as isize, s as isize);
+        let a_flips = if b & ROTATE_90 != 0 {
+            ((a & FLIP_H) << 1) | ((a & FLIP_V) >> 1)
+        } else {
+            a & FLIP_HV
+        };
+        let both_rotate = if a & b & ROTATE_90 != 0 { FLIP_HV } else { 0 };
+        Self::from(((a ^ b) & ROTATE_90) | ((b & FLIP_HV) ^ a_flips ^ both_rotate))
+    }
+
+    #[inline]
+    pub fn inverse(&self) -> Self {
+        match *self {
+            Self::Rotate90 => Self::Rotate90FlipHV,
+            Self::Rotate90FlipHV => Self::Rotate90,
+            n => n,
+        }
+    }
+
+    #[inline]
+    pub fn transform_size(&self, size: (i32, i32)) -> (i32, i32) {
+        if self.has_rotate90() { (size.1, size.0) } else { size }
+    }
+
+    // Where the position pos in an area of size ends up, in the same way
+    // as the pixels of a pattern are drawn.
+    #[inline]
+    pub fn transform_pos(&self, pos: (i32, i32), size: (i32, i32)) -> (i32, i32) {
+        let (i, j) = pos;
+        let (w, h) = size;
+        match *self {
+            Self::Normal         => (i, j),
+            Self::FlipH          => (w - 1 - i, j),
+            Self::FlipV          => (i, h - 1 - j),
+            Self::FlipHV         => (w - 1 - i, h - 1 - j),
+            Self::Rotate90       => (h - 1 - j, i),
+            Self::Rotate90FlipH  => (j, i),
+            Self::Rotate90FlipV  => (h - 1 - j, w - 1 - i),
+            Self::Rotate90FlipHV => (j, w - 1 - i),
+        }
+    }
+
+    // rect is x, y, w, h (w, h > 0) in an area of size.
+    #[inline]
+    pub fn transform_rect(&self, rect: [i32; 4], size: (i32, i32)) -> [i32; 4] {
+        let (x0, y0) = self.transform_pos((rect[0], rect[1]), size);
+        let (x1, y1) = self.transform_pos((rect[0] + rect[2] - 1, rect[1] + rect[3] - 1), size);
+        let (w, h) = self.transform_size((rect[2], rect[3]));
+        [x0.min(x1), y0.min(y1), w, h]
+    }
+}
+
+// What base_symmetry applies to. Texture flips/rotates each tile and
+// sprite in place. Screen flips/rotates the whole screen (e.g. for a
+// rotated monitor): the cell and sprite positions are transformed too and
+// the rendered images take the rotated size. The positions given to the
+// APIs (cells, view_pos, sprite pos, windows) stay unrotated.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum SymmetryScope {
+    #[default]
+    Texture,
+    Screen,
+}
+
+// Color math used when a layer or a sprite is drawn over what is underneath.
+// The opacity (and the alpha of the source pixel) weights the result
+// against the destination.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum BlendMode {
+    #[default]
+    Alpha,
+    Add,
+    Subtract,
+    Half,
+    Multiply,
+}
+
+#[inline]
+pub fn blend_pixel(dst: &mut Rgba<u8>, src: Rgba<u8>, mode: BlendMode, opacity: u8) {
+    let a = src[3] as u32 * opacity as u32 / 255;
+    if a == 0 {
+        return;
+    }
+    // Straight alpha: where dst is (partly) transparent the mode has less
+    // to work on and the source color shows as is.
+    // Weights scaled by 255, out_a255 is their sum so the color stays in range.
+    let da = dst[3] as u32;
+    let out_a255 = a * 255 + da * (255 - a);
+    for c in 0..3 {
+        let (s, d) = (src[c] as u32, dst[c] as u32);
+        let target = match mode {
+            BlendMode::Alpha    => s,
+            BlendMode::Add      => (d + s).min(255),
+            BlendMode::Subtract => d.saturating_sub(s),
+            BlendMode::Half     => (d + s) / 2,
+            BlendMode::Multiply => d * s / 255,
+        };
+        let target = (target * da + s * (255 - da)) / 255;
+        dst[c] = ((target * a * 255 + d * da * (255 - a)) / out_a255) as u8;
+    }
+    dst[3] = (out_a255 / 255) as u8;
+}
+
+pub const BRIGHTNESS_MAX: i32 = 255;
+
+// brightness: -255 (black) ..= 0 (as is) ..= 255 (white)
+#[inline]
+pub fn brighten(pixel: Rgba<u8>, brightness: i32) -> Rgba<u8> {
+    let b = brightness.clamp(-BRIGHTNESS_MAX, BRIGHTNESS_MAX);
+    if b == 0 {
+        return pixel;
+    }
+    let mut result = pixel;
+    for c in 0..3 {
+        let v = pixel[c] as i32;
+        result[c] = if b > 0 {
+            v + (255 - v) * b / 255
+        } else {
+            v * (255 + b) / 255
+        } as u8;
+    }
+    result
+}
+
+// Applied to each pixel as pixel * mul / 255 + add, for tints and flashes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ColorTransform {
+    pub mul: Rgba<u8>,
+    pub add: [i16; 3],
+}
+
+impl Default for ColorTransform {
+    fn default() -> Self {
+        Self {
+            mul: Rgba([255, 255, 255, 255]),
+            add: [0, 0, 0],
+        }
+    }
+}
+
+impl ColorTransform {
+    pub fn new(mul: Rgba<u8>, add: [i16; 3]) -> Self {
+        Self {
+            mul,
+            add,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    #[inline]
+    pub fn apply(&self, pixel: Rgba<u8>) -> Rgba<u8> {
+        let mut result = pixel;
+        for c in 0..3 {
+            let v = pixel[c] as i32 * self.mul[c] as i32 / 255 + self.add[c] as i32;
+            result[c] = v.clamp(0, 255) as u8;
+        }
+        result[3] = (pixel[3] as u32 * self.mul[3] as u32 / 255) as u8;
+        result
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowRegion {
+    // x, y, width, height
+    Rect(i32, i32, i32, i32),
+    // top y, then (left, right) of each scanline from there, right exclusive
+    Spans(i32, Vec<(i32, i32)>),
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum WindowMode {
+    #[default]
+    Inside,
+    Outside,
+}
+
+// A region in view pixels (before pixel_scale) in which a layer is shown
+// (Inside) or hidden (Outside).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClipWindow {
+    pub region: WindowRegion,
+    pub mode: WindowMode,
+}
+
+impl ClipWindow {
+    pub fn new(region: WindowRegion, mode: WindowMode) -> Self {
+        Self {
+            region,
+            mode,
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        match &self.region {
+            WindowRegion::Rect(rx, ry, rw, rh) => {
+                x >= *rx && x < rx + rw && y >= *ry && y < ry + rh
+            }
+            WindowRegion::Spans(top, spans) => {
+                if y < *top {
+                    return false;
+                }
+                match spans.get((y - top) as usize) {
+                    Some((left, right)) => x >= *left && x < *right,
+                    None => false,
+                }
+            }
+        }
+    }
+
+    #[inline]
+    pub fn shows(&self, x: i32, y: i32) -> bool {
+        self.contains(x, y) == (self.mode == WindowMode::Inside)
+    }
+
+    // The parts of clip (x, y, width, height) in which the layer is shown.
+    pub fn visible_rects(&self, clip: [i32; 4]) -> Vec<[i32; 4]> {
+        let (cx0, cy0, cx1, cy1) = (clip[0], clip[1], clip[0] + clip[2], clip[1] + clip[3]);
+        let mut rects = Vec::new();
+        for y in cy0..cy1 {
+            let (left, right) = match &self.region {
+                WindowRegion::Rect(rx, ry, rw, rh) if y >= *ry && y < ry + rh => (*rx, rx + rw),
+                WindowRegion::Spans(top, spans) if y >= *top => {
+                    spans.get((y - top) as usize).copied().unwrap_or((0, 0))
+                }
+                _ => (0, 0),
+            };
+            let (left, right) = (left.clamp(cx0, cx1), right.clamp(cx0, cx1));
+            let runs = match self.mode {
+                WindowMode::Inside => vec![(left, right)],
+                WindowMode::Outside if left < right => vec![(cx0, left), (right, cx1)],
+                WindowMode::Outside => vec![(cx0, cx1)],
+            };
+            for (x0, x1) in runs {
+                if x0 >= x1 {
+                    continue;
+                }
+                // extend the rect of the previous scanline if it has the same span
+                match rects.iter_mut().find(|r: &&mut [i32; 4]| r[0] == x0 && r[2] == x1 - x0 && r[1] + r[3] == y) {
+                    Some(r) => r[3] += 1,
+                    None => rects.push([x0, y, x1 - x0, 1]),
+                }
+            }
+        }
+        rects
+    }
 }
 
 pub fn draw(
@@ -150,7 +402,7 @@ pub fn draw(
         Symmetry::Rotate90FlipV  => (( 0,-1), (-1, 0), (y!(draw_size) - 1, x!(draw_size) - 1)),
         Symmetry::Rotate90FlipHV => (( 0,-1), ( 1, 0), (                0, x!(draw_size) - 1)),
     };
-    let mut pattern = pattern.into_iter();
+    let mut pattern = pattern.iter();
     let mut y_j = (0, 0);
     for _ in 0..y!(draw_size) {
         let mut x_i = (0, 0);
@@ -178,3 +430,144 @@ pub fn draw(
         y!(y_j) += y!(unit_j);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: (i32, i32) = (3, 5);
+
+    fn all_symmetries() -> impl Iterator<Item = Symmetry> {
+        (0..8).map(Symmetry::from)
+    }
+
+    #[test]
+    fn followed_by_applies_self_then_s() {
+        for a in all_symmetries() {
+            for b in all_symmetries() {
+                let ab = a.followed_by(b);
+                for j in 0..SIZE.1 {
+                    for i in 0..SIZE.0 {
+                        let pos = b.transform_pos(a.transform_pos((i, j), SIZE), a.transform_size(SIZE));
+                        assert_eq!(ab.transform_pos((i, j), SIZE), pos, "{:?} then {:?}", a, b);
+                    }
+                }
+                assert_eq!(ab.transform_size(SIZE), b.transform_size(a.transform_size(SIZE)));
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_round_trips() {
+        for a in all_symmetries() {
+            assert_eq!(a.followed_by(a.inverse()), Symmetry::Normal, "{:?}", a);
+            assert_eq!(a.inverse().followed_by(a), Symmetry::Normal, "{:?}", a);
+            for j in 0..SIZE.1 {
+                for i in 0..SIZE.0 {
+                    let pos = a.transform_pos((i, j), SIZE);
+                    assert_eq!(a.inverse().transform_pos(pos, a.transform_size(SIZE)), (i, j), "{:?}", a);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transform_rect_matches_transform_pos() {
+        let rect = [1, 2, 2, 3];
+        for a in all_symmetries() {
+            let r = a.transform_rect(rect, SIZE);
+            for j in rect[1]..rect[1] + rect[3] {
+                for i in rect[0]..rect[0] + rect[2] {
+                    let (x, y) = a.transform_pos((i, j), SIZE);
+                    assert!(x >= r[0] && x < r[0] + r[2] && y >= r[1] && y < r[1] + r[3], "{:?}", a);
+                }
+            }
+            assert_eq!(r[2] * r[3], rect[2] * rect[3]);
+        }
+    }
+
+    #[test]
+    fn transform_pos_matches_draw() {
+        // one 8x8 pattern with a distinct palette index per pixel
+        let pattern: Vec<u64> = (0..8u64)
+            .map(|j| (0..8u64).fold(0, |row, i| (row << 8) | (j * 8 + i)))
+            .collect();
+        let mut color_tbl = [Rgba([0, 0, 0, 0]); NUM_PALETTE_COL];
+        for (n, color) in color_tbl.iter_mut().enumerate() {
+            *color = Rgba([n as u8, 0, 0, 255]);
+        }
+        for a in all_symmetries() {
+            let mut image = RgbaImage::new(8, 8);
+            draw((1, 1), &pattern, &color_tbl, a, (0, 0), (1, 1), &mut image);
+            for j in 0..8 {
+                for i in 0..8 {
+                    let (x, y) = a.transform_pos((i, j), (8, 8));
+                    assert_eq!(image.get_pixel(x as u32, y as u32)[0], (j * 8 + i) as u8, "{:?}", a);
+                }
+            }
+        }
+    }
+
+    const BLEND_MODES: [BlendMode; 5] = [BlendMode::Alpha, BlendMode::Add, BlendMode::Subtract, BlendMode::Half, BlendMode::Multiply];
+
+    fn blended(dst: [u8; 4], src: [u8; 4], mode: BlendMode, opacity: u8) -> [u8; 4] {
+        let mut dst = Rgba(dst);
+        blend_pixel(&mut dst, Rgba(src), mode, opacity);
+        dst.0
+    }
+
+    #[test]
+    fn blend_pixel_over_opaque() {
+        let (dst, src) = ([200, 100, 40, 255], [100, 150, 60, 255]);
+        let expected = [
+            [100, 150, 60, 255],
+            [255, 250, 100, 255],
+            [100, 0, 0, 255],
+            [150, 125, 50, 255],
+            [78, 58, 9, 255],
+        ];
+        for (mode, expected) in BLEND_MODES.into_iter().zip(expected) {
+            assert_eq!(blended(dst, src, mode, 255), expected, "{:?}", mode);
+        }
+        // half opacity goes halfway to the full result
+        assert_eq!(blended(dst, src, BlendMode::Alpha, 128), [149, 125, 50, 255]);
+        assert_eq!(blended(dst, [0, 0, 0, 0], BlendMode::Add, 255), dst);
+    }
+
+    #[test]
+    fn blend_pixel_over_transparent() {
+        let src = [100, 150, 60, 128];
+        for mode in BLEND_MODES {
+            assert_eq!(blended([200, 100, 40, 0], src, mode, 255), src, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn blend_pixel_over_partly_transparent() {
+        let (dst, src) = ([200, 100, 40, 128], [100, 150, 60, 255]);
+        for mode in BLEND_MODES {
+            let out = blended(dst, src, mode, 255);
+            assert_eq!(out[3], 255, "{:?}", mode);
+            // between the source color and the opaque result of the mode
+            let opaque = blended([200, 100, 40, 255], src, mode, 255);
+            for c in 0..3 {
+                assert!(out[c] >= src[c].min(opaque[c]) && out[c] <= src[c].max(opaque[c]), "{:?}", mode);
+            }
+        }
+        assert_eq!(blended([255, 255, 255, 128], [255, 255, 255, 64], BlendMode::Alpha, 255), [255, 255, 255, 159]);
+    }
+
+    #[test]
+    fn blend_pixel_stays_in_range() {
+        // white over white is white for every mode but Subtract
+        for mode in [BlendMode::Alpha, BlendMode::Add, BlendMode::Half, BlendMode::Multiply] {
+            for da in (0..=255).step_by(15) {
+                for sa in (15..=255).step_by(15) {
+                    let out = blended([255, 255, 255, da], [255, 255, 255, sa], mode, 255);
+                    assert_eq!(out[..3], [255, 255, 255], "{:?} {} {}", mode, da, sa);
+                    assert!(out[3] >= da.max(sa), "{:?} {} {}", mode, da, sa);
+                }
+            }
+        }
+    }
+}
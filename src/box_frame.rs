@@ -0,0 +1,87 @@
+use super::bg_resources::AChar;
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum BoxFill {
+    #[default]
+    Keep,   // leave the interior as it is
+    Fill,   // BoxFrame::fill
+    Clear,  // AChar::default()
+}
+
+// Nine-slice frame for BgPlane::draw_box. The edges repeat their AChars
+// in order along the side, so an edge can be made of several tiles.
+#[derive(Clone, PartialEq, Eq)]
+pub struct BoxFrame {
+    pub top_left: AChar,
+    pub top: Vec<AChar>,
+    pub top_right: AChar,
+    pub left: Vec<AChar>,
+    pub fill: AChar,
+    pub right: Vec<AChar>,
+    pub bottom_left: AChar,
+    pub bottom: Vec<AChar>,
+    pub bottom_right: AChar,
+}
+
+impl BoxFrame {
+    // top-left, top, top-right, left, fill, right, bottom-left, bottom, bottom-right
+    pub fn new(achars: [AChar; 9]) -> Self {
+        Self {
+            top_left: achars[0],
+            top: vec![achars[1]],
+            top_right: achars[2],
+            left: vec![achars[3]],
+            fill: achars[4],
+            right: vec![achars[5]],
+            bottom_left: achars[6],
+            bottom: vec![achars[7]],
+            bottom_right: achars[8],
+        }
+    }
+
+    pub fn with_top(mut self, top: Vec<AChar>) -> Self {
+        self.top = top;
+        self
+    }
+
+    pub fn with_left(mut self, left: Vec<AChar>) -> Self {
+        self.left = left;
+        self
+    }
+
+    pub fn with_right(mut self, right: Vec<AChar>) -> Self {
+        self.right = right;
+        self
+    }
+
+    pub fn with_bottom(mut self, bottom: Vec<AChar>) -> Self {
+        self.bottom = bottom;
+        self
+    }
+
+    fn edge(edge: &[AChar], n: i32) -> AChar {
+        if edge.is_empty() {
+            AChar::default()
+        } else {
+            edge[n as usize % edge.len()]
+        }
+    }
+
+    // The AChar at (x, y) of a w x h box, None for the interior.
+    pub(crate) fn achar_at(&self, x: i32, y: i32, w: i32, h: i32) -> Option<AChar> {
+        let (left, right) = (x == 0, x == w - 1);
+        let (top, bottom) = (y == 0, y == h - 1);
+        let achar = match (left, right, top, bottom) {
+            (true, _, true, _)         => self.top_left,
+            (false, true, true, _)     => self.top_right,
+            (true, _, false, true)     => self.bottom_left,
+            (false, true, false, true) => self.bottom_right,
+            (_, _, true, _)            => Self::edge(&self.top, x - 1),
+            (_, _, false, true)        => Self::edge(&self.bottom, x - 1),
+            (true, _, _, _)            => Self::edge(&self.left, y - 1),
+            (false, true, _, _)        => Self::edge(&self.right, y - 1),
+            _                          => return None,
+        };
+        Some(achar)
+    }
+}
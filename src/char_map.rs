@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use super::bg_resources::{BgCode, BgPalette, BgSymmetry};
+
+// A character as placed on a BgPlane: a grid of codes (row major) and
+// optional attributes that override the ones given to the string APIs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glyph {
+    pub size: (i32, i32),
+    pub codes: Vec<BgCode>,
+    pub palette: Option<BgPalette>,
+    pub symmetry: Option<BgSymmetry>,
+}
+
+impl Glyph {
+    pub fn new<T: Into<BgCode>>(code: T) -> Self {
+        Self::grid((1, 1), vec![code.into()])
+    }
+
+    pub fn grid(size: (i32, i32), codes: Vec<BgCode>) -> Self {
+        let size = (size.0.max(1), size.1.max(1));
+        let mut codes = codes;
+        codes.resize((size.0 * size.1) as usize, BgCode::default());
+        Self {
+            size,
+            codes,
+            palette: None,
+            symmetry: None,
+        }
+    }
+
+    pub fn with_palette(mut self, palette: BgPalette) -> Self {
+        self.palette = Some(palette);
+        self
+    }
+
+    pub fn with_symmetry(mut self, symmetry: BgSymmetry) -> Self {
+        self.symmetry = Some(symmetry);
+        self
+    }
+
+    pub const fn width(&self) -> i32 {
+        self.size.0
+    }
+
+    pub const fn height(&self) -> i32 {
+        self.size.1
+    }
+
+    pub fn code_at(&self, x: i32, y: i32) -> BgCode {
+        self.codes[(x + y * self.size.0) as usize]
+    }
+}
+
+pub trait CharMap {
+    fn glyph(&self, character: char) -> Option<&Glyph>;
+
+    // Used for the characters glyph() does not know. When this is None too,
+    // nothing is written but the cursor still advances by one cell.
+    fn missing_glyph(&self) -> Option<&Glyph> {
+        None
+    }
+}
+
+// The default map: code = character for 0x00..0x80.
+pub struct AsciiCharMap {
+    glyphs: Vec<Glyph>,
+}
+
+impl Default for AsciiCharMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsciiCharMap {
+    pub fn new() -> Self {
+        Self {
+            glyphs: (0..0x80u32).map(Glyph::new).collect(),
+        }
+    }
+}
+
+impl CharMap for AsciiCharMap {
+    fn glyph(&self, character: char) -> Option<&Glyph> {
+        self.glyphs.get(character as usize)
+    }
+}
+
+#[derive(Default)]
+pub struct TableCharMap {
+    glyphs: HashMap<char, Glyph>,
+    missing_glyph: Option<Glyph>,
+}
+
+impl TableCharMap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_ascii() -> Self {
+        let mut char_map = Self::new();
+        char_map.insert_range('\0'..='\x7f', 0);
+        char_map
+    }
+
+    pub fn insert(&mut self, character: char, glyph: Glyph) -> &mut Self {
+        self.glyphs.insert(character, glyph);
+        self
+    }
+
+    // Maps consecutive characters to consecutive codes from first_code.
+    pub fn insert_range(&mut self, characters: RangeInclusive<char>, first_code: BgCode) -> &mut Self {
+        for (i, character) in characters.enumerate() {
+            self.glyphs.insert(character, Glyph::new(first_code + i as BgCode));
+        }
+        self
+    }
+
+    // Maps consecutive characters to glyphs of two codes side by side
+    // (full-width), taking two consecutive codes each from first_code.
+    pub fn insert_wide_range(&mut self, characters: RangeInclusive<char>, first_code: BgCode) -> &mut Self {
+        for (i, character) in characters.enumerate() {
+            let code = first_code + i as BgCode * 2;
+            self.glyphs.insert(character, Glyph::grid((2, 1), vec![code, code + 1]));
+        }
+        self
+    }
+
+    pub fn remove(&mut self, character: char) -> &mut Self {
+        self.glyphs.remove(&character);
+        self
+    }
+
+    pub fn set_missing_glyph(&mut self, missing_glyph: Option<Glyph>) -> &mut Self {
+        self.missing_glyph = missing_glyph;
+        self
+    }
+}
+
+impl CharMap for TableCharMap {
+    fn glyph(&self, character: char) -> Option<&Glyph> {
+        self.glyphs.get(&character)
+    }
+
+    fn missing_glyph(&self) -> Option<&Glyph> {
+        self.missing_glyph.as_ref()
+    }
+}
@@ -1,6 +1,6 @@
-pub use super::bgsp_common::{SpPos, SpCode, SpPalette, SpSymmetry};
+pub use super::bgsp_common::{SpPos, SpCode, SpPalette, SpSymmetry, BlendMode, ColorTransform};
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone)]
 pub struct ClassicSprite {
     pub pos: SpPos,
     pub code: SpCode,
@@ -8,6 +8,27 @@ pub struct ClassicSprite {
     pub symmetry: SpSymmetry,
     pub visible: bool,
     pub priority: i32,
+    pub blend_mode: BlendMode,
+    pub opacity: u8,
+    pub mosaic: i32,
+    pub tint: ColorTransform,
+}
+
+impl Default for ClassicSprite {
+    fn default() -> Self {
+        Self {
+            pos: SpPos::default(),
+            code: SpCode::default(),
+            palette: SpPalette::default(),
+            symmetry: SpSymmetry::default(),
+            visible: false,
+            priority: 0,
+            blend_mode: BlendMode::default(),
+            opacity: 255,
+            mosaic: 1,
+            tint: ColorTransform::default(),
+        }
+    }
 }
 
 impl ClassicSprite {
@@ -44,4 +65,24 @@ impl ClassicSprite {
         self.priority = priority;
         self
     }
+
+    pub fn blend_mode(&mut self, blend_mode: BlendMode) -> &mut Self {
+        self.blend_mode = blend_mode;
+        self
+    }
+
+    pub fn opacity(&mut self, opacity: u8) -> &mut Self {
+        self.opacity = opacity;
+        self
+    }
+
+    pub fn mosaic(&mut self, mosaic: i32) -> &mut Self {
+        self.mosaic = mosaic;
+        self
+    }
+
+    pub fn tint(&mut self, tint: ColorTransform) -> &mut Self {
+        self.tint = tint;
+        self
+    }
 }
\ No newline at end of file
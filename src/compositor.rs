@@ -0,0 +1,326 @@
+use std::cmp::Reverse;
+
+use super::bgsp_common::{self, Rgba, RgbaImage, BlendMode, ClipWindow, ColorTransform, Symmetry, BRIGHTNESS_MAX};
+use super::bg_plane::BgPlane;
+use super::sp_resources::{SpResources, ShadowHighlight};
+
+#[derive(Clone, Copy)]
+enum Layer {
+    BgLow(usize),
+    BgHigh(usize),
+    Sprite(usize),
+}
+
+// Layers are drawn from the largest priority value to the smallest.
+// At the same priority the BgPlanes are behind the sprites, and the
+// BgPlanes keep the order in which they are given (the first is the
+// farthest).
+const CLASS_BG: u8 = 0;
+const CLASS_SP: u8 = 1;
+
+#[inline(always)]
+fn u_mod(x: i32, p: i32) -> i32 {
+    (x % p + p) % p
+}
+
+type LayerKey = (Reverse<i32>, u8, usize);
+
+struct LayerParams<'w> {
+    blend_mode: BlendMode,
+    opacity: u8,
+    window: Option<&'w ClipWindow>,
+    mosaic: i32,
+    // of the source image, scaled to the Compositor's in blit
+    pixel_scale: i32,
+    wrap_source: bool,
+    brightness: i32,
+    tint: ColorTransform,
+    screen_symmetry: Symmetry,
+    operators: Option<(&'w RgbaImage, ShadowHighlight)>,
+}
+
+pub struct Compositor {
+    view_size: (i32, i32),
+    pixel_scale: i32,
+    backdrop: Rgba<u8>,
+    master_brightness: i32,
+    image: RgbaImage,
+}
+
+impl Compositor {
+    // view_size is the output size, i.e. the rotated view when the layers
+    // use SymmetryScope::Screen with a rotating base_symmetry.
+    pub fn new(view_size: (i32, i32), pixel_scale: i32) -> Self {
+        let image = RgbaImage::new((view_size.0 * pixel_scale) as u32, (view_size.1 * pixel_scale) as u32);
+        Self {
+            view_size,
+            pixel_scale,
+            backdrop: Rgba([0, 0, 0, 0]),
+            master_brightness: 0,
+            image,
+        }
+    }
+
+    pub const fn view_size(&self) -> (i32, i32) {
+        self.view_size
+    }
+
+    pub const fn pixel_scale(&self) -> i32 {
+        self.pixel_scale
+    }
+
+    // The image is reallocated. Layers of another pixel_scale are scaled
+    // to this one when composed.
+    pub fn set_pixel_scale(&mut self, pixel_scale: i32) -> &mut Self {
+        if pixel_scale != self.pixel_scale {
+            self.pixel_scale = pixel_scale;
+            self.image = RgbaImage::new((self.view_size.0 * pixel_scale) as u32, (self.view_size.1 * pixel_scale) as u32);
+        }
+        self
+    }
+
+    pub const fn backdrop(&self) -> Rgba<u8> {
+        self.backdrop
+    }
+
+    pub fn set_backdrop(&mut self, backdrop: Rgba<u8>) -> &mut Self {
+        self.backdrop = backdrop;
+        self
+    }
+
+    pub const fn master_brightness(&self) -> i32 {
+        self.master_brightness
+    }
+
+    // Applied to the whole composed image, see Fader for stepping it.
+    pub fn set_master_brightness(&mut self, master_brightness: i32) -> &mut Self {
+        self.master_brightness = master_brightness.clamp(-BRIGHTNESS_MAX, BRIGHTNESS_MAX);
+        self
+    }
+
+    pub fn image(&self) -> &RgbaImage {
+        &self.image
+    }
+
+    // The BgPlanes must have been rendered (BgPlane::rendering) beforehand.
+    pub fn compose(&mut self, bg_planes: &[&BgPlane], sp_resources: &SpResources) -> &RgbaImage {
+        let mut layers: Vec<(LayerKey, Layer)> = Vec::new();
+        for (plane_no, plane) in bg_planes.iter().enumerate() {
+            layers.push(((Reverse(plane.layer_priority()), CLASS_BG, plane_no * 2), Layer::BgLow(plane_no)));
+            if plane.whole_high_image().is_some() {
+                layers.push(((Reverse(plane.high_layer_priority()), CLASS_BG, plane_no * 2 + 1), Layer::BgHigh(plane_no)));
+            }
+        }
+        for (seq, sp_no) in sp_resources.drawing_order().into_iter().enumerate() {
+            layers.push(((Reverse(sp_resources.sp[sp_no].priority), CLASS_SP, seq), Layer::Sprite(sp_no)));
+        }
+        layers.sort_by_key(|(key, _)| *key);
+
+        for pixel in self.image.pixels_mut() {
+            *pixel = self.backdrop;
+        }
+        for (_key, layer) in layers {
+            match layer {
+                Layer::BgLow(plane_no) => {
+                    let plane = bg_planes[plane_no];
+                    self.draw_plane(plane, plane.whole_image());
+                }
+                Layer::BgHigh(plane_no) => {
+                    let plane = bg_planes[plane_no];
+                    if let Some(high_image) = plane.whole_high_image() {
+                        self.draw_plane(plane, high_image);
+                    }
+                }
+                Layer::Sprite(sp_no) => {
+                    let screen_symmetry = sp_resources.screen_symmetry();
+                    let (view_w, view_h) = screen_symmetry.transform_size(self.view_size);
+                    if let Some(t) = sp_resources.sprite_texture(sp_no, view_w, view_h) {
+                        let a_sp = &sp_resources.sp[sp_no];
+                        let index_texture = sp_resources.shadow_highlight().and_then(|_| sp_resources.sprite_index_texture(sp_no));
+                        let operators = index_texture.as_deref().zip(sp_resources.shadow_highlight());
+                        let sp_scale = sp_resources.texture_bank.borrow().pixel_scale();
+                        let src_rect = [0, 0, t.width() as i32, t.height() as i32];
+                        let size = (src_rect[2] / sp_scale, src_rect[3] / sp_scale);
+                        let (x, y) = sp_resources.sprite_screen_pos(sp_no, size, view_w, view_h);
+                        let dst_pos = (x * self.pixel_scale, y * self.pixel_scale);
+                        let params = LayerParams {
+                            blend_mode: a_sp.blend_mode,
+                            opacity: a_sp.opacity,
+                            window: sp_resources.window(),
+                            mosaic: a_sp.mosaic,
+                            pixel_scale: sp_scale,
+                            wrap_source: false,
+                            brightness: sp_resources.brightness(),
+                            tint: a_sp.tint,
+                            screen_symmetry,
+                            operators,
+                        };
+                        self.blit(&t, src_rect, dst_pos, &params);
+                    }
+                }
+            }
+        }
+        if self.master_brightness != 0 {
+            for pixel in self.image.pixels_mut() {
+                *pixel = bgsp_common::brighten(*pixel, self.master_brightness);
+            }
+        }
+        &self.image
+    }
+
+    fn draw_plane(&mut self, plane: &BgPlane, source: &RgbaImage) {
+        let params = LayerParams {
+            blend_mode: plane.blend_mode(),
+            opacity: plane.opacity(),
+            window: plane.window(),
+            mosaic: plane.mosaic(),
+            pixel_scale: plane.pixel_scale(),
+            wrap_source: true,
+            brightness: plane.brightness(),
+            tint: ColorTransform::default(),
+            screen_symmetry: plane.screen_symmetry(),
+            operators: None,
+        };
+        for (dst, src) in plane.draw_rects() {
+            let src_rect = [src[0] as i32, src[1] as i32, src[2] as i32, src[3] as i32];
+            let dst_pos = (dst[0] as i32 * self.pixel_scale / params.pixel_scale, dst[1] as i32 * self.pixel_scale / params.pixel_scale);
+            self.blit(source, src_rect, dst_pos, &params);
+        }
+    }
+
+    // Mosaic blocks are aligned to the view, so the layers and sprites
+    // with the same block size are pixelated together. src_rect is in
+    // source pixels, dst_pos in the Compositor's.
+    fn blit(&mut self, source: &RgbaImage, src_rect: [i32; 4], dst_pos: (i32, i32), params: &LayerParams) {
+        let (dst_w, dst_h) = (self.image.width() as i32, self.image.height() as i32);
+        let (src_w, src_h) = (source.width() as i32, source.height() as i32);
+        let (dst_scale, src_scale) = (self.pixel_scale, params.pixel_scale);
+        let to_src = |d: i32| (d * src_scale).div_euclid(dst_scale);
+        let block = params.mosaic.max(1) * dst_scale;
+        let tinted = !params.tint.is_identity();
+        for j in 0..src_rect[3] * dst_scale / src_scale {
+            let dy = dst_pos.1 + j;
+            if dy < 0 || dy >= dst_h {
+                continue;
+            }
+            let sy = src_rect[1] + to_src(dy - dy % block - dst_pos.1);
+            let sy = if params.wrap_source { u_mod(sy, src_h) } else { sy.max(src_rect[1]) };
+            if sy < 0 || sy >= src_h {
+                continue;
+            }
+            for i in 0..src_rect[2] * dst_scale / src_scale {
+                let dx = dst_pos.0 + i;
+                if dx < 0 || dx >= dst_w {
+                    continue;
+                }
+                let sx = src_rect[0] + to_src(dx - dx % block - dst_pos.0);
+                let sx = if params.wrap_source { u_mod(sx, src_w) } else { sx.max(src_rect[0]) };
+                if sx < 0 || sx >= src_w {
+                    continue;
+                }
+                if let Some(window) = params.window {
+                    // windows are given in the unrotated view
+                    let (wx, wy) = params.screen_symmetry.inverse().transform_pos((dx / self.pixel_scale, dy / self.pixel_scale), self.view_size);
+                    if !window.shows(wx, wy) {
+                        continue;
+                    }
+                }
+                if let Some((index_texture, sh)) = params.operators {
+                    let index = index_texture.get_pixel(sx as u32, sy as u32)[0];
+                    if sh.apply(self.image.get_pixel_mut(dx as u32, dy as u32), index) {
+                        continue;
+                    }
+                }
+                let mut src = *source.get_pixel(sx as u32, sy as u32);
+                if tinted {
+                    src = params.tint.apply(src);
+                }
+                let src = bgsp_common::brighten(src, params.brightness);
+                let dst = self.image.get_pixel_mut(dx as u32, dy as u32);
+                bgsp_common::blend_pixel(dst, src, params.blend_mode, params.opacity);
+            }
+        }
+    }
+}
+
+// Steps a brightness level frame by frame for fade-in/out and flashes.
+// Feed level() to Compositor::set_master_brightness or a layer brightness.
+#[derive(Debug, Default, Clone)]
+pub struct Fader {
+    level: i32,
+    target: i32,
+    remaining_frames: i32,
+    flash_level: i32,
+    flash_frames: i32,
+}
+
+impl Fader {
+    pub fn new(level: i32) -> Self {
+        let level = level.clamp(-BRIGHTNESS_MAX, BRIGHTNESS_MAX);
+        Self {
+            level,
+            target: level,
+            ..Default::default()
+        }
+    }
+
+    pub fn level(&self) -> i32 {
+        if self.flash_frames > 0 {
+            self.flash_level
+        } else {
+            self.level
+        }
+    }
+
+    pub fn is_fading(&self) -> bool {
+        self.remaining_frames > 0 || self.flash_frames > 0
+    }
+
+    pub fn set_level(&mut self, level: i32) -> &mut Self {
+        self.level = level.clamp(-BRIGHTNESS_MAX, BRIGHTNESS_MAX);
+        self.target = self.level;
+        self.remaining_frames = 0;
+        self
+    }
+
+    pub fn fade_to(&mut self, target: i32, frames: i32) -> &mut Self {
+        self.target = target.clamp(-BRIGHTNESS_MAX, BRIGHTNESS_MAX);
+        self.remaining_frames = frames.max(0);
+        if self.remaining_frames == 0 {
+            self.level = self.target;
+        }
+        self
+    }
+
+    pub fn fade_in(&mut self, frames: i32) -> &mut Self {
+        self.fade_to(0, frames)
+    }
+
+    pub fn fade_out(&mut self, frames: i32) -> &mut Self {
+        self.fade_to(-BRIGHTNESS_MAX, frames)
+    }
+
+    pub fn fade_out_white(&mut self, frames: i32) -> &mut Self {
+        self.fade_to(BRIGHTNESS_MAX, frames)
+    }
+
+    // Overrides the level for the given frames, then the fade goes on.
+    pub fn flash(&mut self, flash_level: i32, frames: i32) -> &mut Self {
+        self.flash_level = flash_level.clamp(-BRIGHTNESS_MAX, BRIGHTNESS_MAX);
+        self.flash_frames = frames.max(0);
+        self
+    }
+
+    // Advances one frame and returns the level for that frame.
+    pub fn step(&mut self) -> i32 {
+        if self.remaining_frames > 0 {
+            self.level += (self.target - self.level) / self.remaining_frames;
+            self.remaining_frames -= 1;
+        }
+        let level = self.level();
+        if self.flash_frames > 0 {
+            self.flash_frames -= 1;
+        }
+        level
+    }
+}
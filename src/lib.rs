@@ -1,8 +1,16 @@
 pub mod bgsp_common;
 mod bg_resources;
 pub mod bg_plane;
+mod box_frame;
+mod char_map;
+mod map_stream;
+mod markup;
+mod metatile;
+mod number_format;
 mod classic_sprite;
 pub mod sp_resources;
+mod sp_slot;
+pub mod compositor;
 mod texture_bank;
 
 #[macro_export]
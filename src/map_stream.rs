@@ -0,0 +1,74 @@
+use std::rc::Rc;
+
+use super::bg_resources::{AChar, PATTERN_SIZE};
+
+// A world map larger than the BgPlane buffer, looked up by world cell.
+// Any Fn(i32, i32) -> AChar is a MapSource.
+pub trait MapSource {
+    fn achar_at(&self, x: i32, y: i32) -> AChar;
+}
+
+impl<F: Fn(i32, i32) -> AChar> MapSource for F {
+    fn achar_at(&self, x: i32, y: i32) -> AChar {
+        self(x, y)
+    }
+}
+
+// Keeps the cells around the view loaded in the BgPlane buffer used as a
+// ring buffer: world cell (x, y) is at (u_mod(x, w), u_mod(y, h)).
+pub(crate) struct MapStreamer<'a> {
+    pub(crate) source: Rc<dyn MapSource + 'a>,
+    pub(crate) margin: i32,
+    // x, y, w, h in world cells
+    pub(crate) loaded: Option<[i32; 4]>,
+}
+
+impl<'a> MapStreamer<'a> {
+    pub(crate) fn new(source: Rc<dyn MapSource + 'a>, margin: i32) -> Self {
+        Self {
+            source,
+            margin: margin.max(0),
+            loaded: None,
+        }
+    }
+
+    // The cells to be loaded for the view, the margin is narrowed so that
+    // the region fits in the buffer.
+    pub(crate) fn region(&self, view_pos: (i32, i32), view_size: (i32, i32), buffer_size: (i32, i32)) -> [i32; 4] {
+        let cell = PATTERN_SIZE as i32;
+        let axis = |pos: i32, size: i32, buffer: i32| {
+            let first = pos.div_euclid(cell);
+            let count = (pos + size.max(1) - 1).div_euclid(cell) - first + 1;
+            let margin = self.margin.min((buffer - count) / 2).max(0);
+            (first - margin, (count + margin * 2).min(buffer))
+        };
+        let (x, w) = axis(view_pos.0, view_size.0, buffer_size.0);
+        let (y, h) = axis(view_pos.1, view_size.1, buffer_size.1);
+        [x, y, w, h]
+    }
+
+    // Cells of region not in the loaded one, as runs of (x, y, w).
+    pub(crate) fn exposed_runs(&self, region: [i32; 4]) -> Vec<(i32, i32, i32)> {
+        let [x, y, w, h] = region;
+        let mut runs = Vec::new();
+        for cy in y..y + h {
+            match self.loaded {
+                Some([lx, ly, lw, lh]) if cy >= ly && cy < ly + lh => {
+                    let (l, r) = (lx.max(x), (lx + lw).min(x + w));
+                    if l >= r {
+                        runs.push((x, cy, w));
+                        continue;
+                    }
+                    if l > x {
+                        runs.push((x, cy, l - x));
+                    }
+                    if r < x + w {
+                        runs.push((r, cy, x + w - r));
+                    }
+                }
+                _ => runs.push((x, cy, w)),
+            }
+        }
+        runs
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+
+use super::bg_resources::{BgPalette, BgSymmetry};
+
+// Inline markup for the string APIs, see BgPlane::put_markup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum MarkupToken {
+    Text(String),
+    Palette(BgPalette),
+    Symmetry(BgSymmetry),
+    Reset,
+    Move(i32, i32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkupErrorKind {
+    Unterminated,
+    UnmatchedClose,
+    UnknownTag(String),
+    InvalidValue(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarkupError {
+    // byte offset of the offending tag in the string
+    pub position: usize,
+    pub kind: MarkupErrorKind,
+}
+
+impl fmt::Display for MarkupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            MarkupErrorKind::Unterminated => write!(f, "unterminated tag at {}", self.position),
+            MarkupErrorKind::UnmatchedClose => write!(f, "unmatched '}}' at {}", self.position),
+            MarkupErrorKind::UnknownTag(tag) => write!(f, "unknown tag {{{}}} at {}", tag, self.position),
+            MarkupErrorKind::InvalidValue(tag) => write!(f, "invalid value in {{{}}} at {}", tag, self.position),
+        }
+    }
+}
+
+impl std::error::Error for MarkupError {}
+
+fn parse_number(value: &str) -> Option<i64> {
+    let value = value.trim();
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_symmetry(value: &str) -> Option<BgSymmetry> {
+    let symmetry = match value.trim() {
+        "Normal"         => BgSymmetry::Normal,
+        "FlipH"          => BgSymmetry::FlipH,
+        "FlipV"          => BgSymmetry::FlipV,
+        "FlipHV"         => BgSymmetry::FlipHV,
+        "Rotate90"       => BgSymmetry::Rotate90,
+        "Rotate90FlipH"  => BgSymmetry::Rotate90FlipH,
+        "Rotate90FlipV"  => BgSymmetry::Rotate90FlipV,
+        "Rotate90FlipHV" => BgSymmetry::Rotate90FlipHV,
+        "Rotate180"      => BgSymmetry::Rotate180,
+        "Rotate270"      => BgSymmetry::Rotate270,
+        other => match parse_number(other) {
+            Some(n @ 0..=7) => BgSymmetry::from(n as isize),
+            _ => return None,
+        },
+    };
+    Some(symmetry)
+}
+
+fn parse_tag(tag: &str, position: usize) -> Result<MarkupToken, MarkupError> {
+    let invalid = || MarkupError { position, kind: MarkupErrorKind::InvalidValue(tag.to_string()) };
+    if tag == "/" {
+        return Ok(MarkupToken::Reset);
+    }
+    if let Some(value) = tag.strip_prefix('@') {
+        let (x, y) = value.split_once(',').ok_or_else(invalid)?;
+        let x = parse_number(x).ok_or_else(invalid)?;
+        let y = parse_number(y).ok_or_else(invalid)?;
+        return Ok(MarkupToken::Move(x as i32, y as i32));
+    }
+    match tag.split_once(':') {
+        Some(("p", value)) => {
+            let palette = parse_number(value).filter(|n| *n >= 0).ok_or_else(invalid)?;
+            Ok(MarkupToken::Palette(palette as BgPalette))
+        }
+        Some(("s", value)) => {
+            Ok(MarkupToken::Symmetry(parse_symmetry(value).ok_or_else(invalid)?))
+        }
+        _ => Err(MarkupError { position, kind: MarkupErrorKind::UnknownTag(tag.to_string()) }),
+    }
+}
+
+pub(crate) fn parse_markup(text: &str) -> Result<Vec<MarkupToken>, MarkupError> {
+    let mut tokens = Vec::new();
+    let mut plain = String::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((position, character)) = chars.next() {
+        match character {
+            '{' if matches!(chars.peek(), Some((_, '{'))) => {
+                chars.next();
+                plain.push('{');
+            }
+            '}' if matches!(chars.peek(), Some((_, '}'))) => {
+                chars.next();
+                plain.push('}');
+            }
+            '{' => {
+                let rest = &text[position + 1..];
+                let end = rest.find('}').ok_or(MarkupError { position, kind: MarkupErrorKind::Unterminated })?;
+                let tag = &rest[..end];
+                if tag.contains('{') {
+                    return Err(MarkupError { position, kind: MarkupErrorKind::Unterminated });
+                }
+                if !plain.is_empty() {
+                    tokens.push(MarkupToken::Text(std::mem::take(&mut plain)));
+                }
+                tokens.push(parse_tag(tag, position)?);
+                while let Some((p, _)) = chars.peek() {
+                    if *p > position + end + 1 {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            '}' => return Err(MarkupError { position, kind: MarkupErrorKind::UnmatchedClose }),
+            _ => plain.push(character),
+        }
+    }
+    if !plain.is_empty() {
+        tokens.push(MarkupToken::Text(plain));
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_at(text: &str) -> (usize, MarkupErrorKind) {
+        let error = parse_markup(text).unwrap_err();
+        (error.position, error.kind)
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(parse_markup("a{{b}}c").unwrap(), vec![MarkupToken::Text("a{b}c".to_string())]);
+        assert_eq!(parse_markup("{{}}").unwrap(), vec![MarkupToken::Text("{}".to_string())]);
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(
+            parse_markup("ab{p:5}c{s:FlipH}{/}d").unwrap(),
+            vec![
+                MarkupToken::Text("ab".to_string()),
+                MarkupToken::Palette(5),
+                MarkupToken::Text("c".to_string()),
+                MarkupToken::Symmetry(BgSymmetry::FlipH),
+                MarkupToken::Reset,
+                MarkupToken::Text("d".to_string()),
+            ]
+        );
+        assert_eq!(parse_markup("{s:3}").unwrap(), vec![MarkupToken::Symmetry(BgSymmetry::FlipHV)]);
+    }
+
+    #[test]
+    fn move_tag() {
+        assert_eq!(parse_markup("{@3,4}").unwrap(), vec![MarkupToken::Move(3, 4)]);
+        assert_eq!(parse_markup("{@0x10, -2}x").unwrap(), vec![MarkupToken::Move(16, -2), MarkupToken::Text("x".to_string())]);
+    }
+
+    #[test]
+    fn unterminated() {
+        assert_eq!(error_at("ab{p:1"), (2, MarkupErrorKind::Unterminated));
+        assert_eq!(error_at("a{p{1}"), (1, MarkupErrorKind::Unterminated));
+    }
+
+    #[test]
+    fn unmatched_close() {
+        assert_eq!(error_at("ab}c"), (2, MarkupErrorKind::UnmatchedClose));
+        assert_eq!(error_at("{p:1}}"), (5, MarkupErrorKind::UnmatchedClose));
+    }
+
+    #[test]
+    fn unknown_tag() {
+        assert_eq!(error_at("xy{q:1}"), (2, MarkupErrorKind::UnknownTag("q:1".to_string())));
+        // positions are byte offsets
+        assert_eq!(error_at("\u{3042}{z}"), (3, MarkupErrorKind::UnknownTag("z".to_string())));
+    }
+
+    #[test]
+    fn invalid_value() {
+        assert_eq!(error_at("{p:-1}"), (0, MarkupErrorKind::InvalidValue("p:-1".to_string())));
+        assert_eq!(error_at("a{s:Spin}"), (1, MarkupErrorKind::InvalidValue("s:Spin".to_string())));
+        assert_eq!(error_at("{@1}"), (0, MarkupErrorKind::InvalidValue("@1".to_string())));
+    }
+}
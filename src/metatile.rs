@@ -0,0 +1,95 @@
+use super::bg_resources::{AChar, BgSymmetry};
+
+// A block of cells placed as a unit (e.g. 2x2 cells for a 16x16 tile),
+// row major.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Metatile {
+    pub size: (i32, i32),
+    pub achars: Vec<AChar>,
+}
+
+impl Metatile {
+    pub fn new(size: (i32, i32), achars: Vec<AChar>) -> Self {
+        let size = (size.0.max(1), size.1.max(1));
+        let mut achars = achars;
+        achars.resize((size.0 * size.1) as usize, AChar::default());
+        Self {
+            size,
+            achars,
+        }
+    }
+
+    pub const fn width(&self) -> i32 {
+        self.size.0
+    }
+
+    pub const fn height(&self) -> i32 {
+        self.size.1
+    }
+
+    pub fn achar_at(&self, x: i32, y: i32) -> AChar {
+        self.achars[(x + y * self.size.0) as usize]
+    }
+
+    // The whole block flipped/rotated: the cells move and each cell's own
+    // symmetry is combined with the block's.
+    pub fn transformed(&self, symmetry: BgSymmetry) -> Self {
+        if symmetry == BgSymmetry::Normal {
+            return self.clone();
+        }
+        let size = symmetry.transform_size(self.size);
+        let mut achars = vec![AChar::default(); self.achars.len()];
+        for j in 0..self.size.1 {
+            for i in 0..self.size.0 {
+                let (x, y) = symmetry.transform_pos((i, j), self.size);
+                let mut achar = self.achar_at(i, j);
+                achar.symmetry = achar.symmetry.followed_by(symmetry);
+                achars[(x + y * size.0) as usize] = achar;
+            }
+        }
+        Self {
+            size,
+            achars,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct MetatileTable {
+    metatiles: Vec<Metatile>,
+}
+
+impl MetatileTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Returns the index of the added metatile.
+    pub fn push(&mut self, metatile: Metatile) -> usize {
+        self.metatiles.push(metatile);
+        self.metatiles.len() - 1
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Metatile> {
+        self.metatiles.get(index)
+    }
+
+    pub fn set(&mut self, index: usize, metatile: Metatile) -> &mut Self {
+        if let Some(m) = self.metatiles.get_mut(index) {
+            *m = metatile;
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.metatiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metatiles.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Metatile> {
+        self.metatiles.iter()
+    }
+}
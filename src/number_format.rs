@@ -0,0 +1,147 @@
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum SignMode {
+    #[default]
+    Negative,   // '-' for negative values only
+    Always,     // '+' or '-'
+    Space,      // ' ' or '-'
+}
+
+// Right-aligned number layout for BgPlane::put_number and friends.
+// Values wider than width are written in full.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct NumberFormat {
+    pub width: usize,
+    pub radix: u32,
+    pub zero_pad: bool,
+    pub sign: SignMode,
+    pub upper_case: bool,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        Self {
+            width: 0,
+            radix: 10,
+            zero_pad: false,
+            sign: SignMode::default(),
+            upper_case: true,
+        }
+    }
+}
+
+impl NumberFormat {
+    pub fn decimal(width: usize) -> Self {
+        Self {
+            width,
+            ..Default::default()
+        }
+    }
+
+    pub fn hex(width: usize) -> Self {
+        Self {
+            width,
+            radix: 16,
+            zero_pad: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_radix(mut self, radix: u32) -> Self {
+        self.radix = radix.clamp(2, 36);
+        self
+    }
+
+    pub fn with_zero_pad(mut self, zero_pad: bool) -> Self {
+        self.zero_pad = zero_pad;
+        self
+    }
+
+    pub fn with_sign(mut self, sign: SignMode) -> Self {
+        self.sign = sign;
+        self
+    }
+
+    pub fn with_upper_case(mut self, upper_case: bool) -> Self {
+        self.upper_case = upper_case;
+        self
+    }
+
+    pub fn format(&self, value: i64) -> String {
+        let radix = self.radix.clamp(2, 36) as u64;
+        let mut magnitude = value.unsigned_abs();
+        let mut digits = Vec::new();
+        loop {
+            let digit = std::char::from_digit((magnitude % radix) as u32, radix as u32).unwrap_or('?');
+            digits.push(if self.upper_case { digit.to_ascii_uppercase() } else { digit });
+            magnitude /= radix;
+            if magnitude == 0 {
+                break;
+            }
+        }
+        let sign = match (value < 0, self.sign) {
+            (true, _) => Some('-'),
+            (false, SignMode::Always) => Some('+'),
+            (false, SignMode::Space) => Some(' '),
+            (false, SignMode::Negative) => None,
+        };
+        let len = digits.len() + sign.is_some() as usize;
+        let pad = self.width.saturating_sub(len);
+        let mut result = String::with_capacity(len + pad);
+        if self.zero_pad {
+            result.extend(sign);
+            result.extend(std::iter::repeat_n('0', pad));
+        } else {
+            result.extend(std::iter::repeat_n(' ', pad));
+            result.extend(sign);
+        }
+        result.extend(digits.iter().rev());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_pad_with_sign() {
+        let format = NumberFormat::decimal(5).with_zero_pad(true);
+        assert_eq!(format.format(-42), "-0042");
+        assert_eq!(format.format(42), "00042");
+        assert_eq!(format.with_sign(SignMode::Always).format(42), "+0042");
+    }
+
+    #[test]
+    fn sign_modes() {
+        let format = NumberFormat::decimal(4);
+        assert_eq!(format.format(7), "   7");
+        assert_eq!(format.format(-7), "  -7");
+        assert_eq!(format.with_sign(SignMode::Always).format(7), "  +7");
+        assert_eq!(format.with_sign(SignMode::Always).format(0), "  +0");
+        assert_eq!(format.with_sign(SignMode::Space).format(7), "   7");
+        assert_eq!(format.with_sign(SignMode::Space).with_zero_pad(true).format(7), " 007");
+        assert_eq!(format.with_sign(SignMode::Space).format(-7), "  -7");
+    }
+
+    #[test]
+    fn hex_case() {
+        assert_eq!(NumberFormat::hex(4).format(0xbeef), "BEEF");
+        assert_eq!(NumberFormat::hex(6).with_upper_case(false).format(0xbeef), "00beef");
+        assert_eq!(NumberFormat::hex(0).format(-0x1f), "-1F");
+    }
+
+    #[test]
+    fn extremes() {
+        assert_eq!(NumberFormat::decimal(0).format(i64::MIN), "-9223372036854775808");
+        assert_eq!(NumberFormat::hex(0).format(i64::MIN), "-8000000000000000");
+        assert_eq!(NumberFormat::decimal(0).format(i64::MAX), "9223372036854775807");
+        assert_eq!(NumberFormat::decimal(3).format(0), "  0");
+    }
+
+    #[test]
+    fn wider_than_width() {
+        assert_eq!(NumberFormat::decimal(3).format(123456), "123456");
+        assert_eq!(NumberFormat::decimal(3).with_zero_pad(true).format(-1234), "-1234");
+        assert_eq!(NumberFormat::hex(2).format(0x12345), "12345");
+    }
+}
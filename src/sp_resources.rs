@@ -2,18 +2,80 @@ pub use std::rc::Rc;
 pub use std::cell::RefCell;
 
 pub use super::classic_sprite::*;
+pub use super::sp_slot::{SpHandle, SpHandleError};
+use super::sp_slot::SpSlots;
+use std::ops::Range;
 use super::texture_bank;
 pub type SpTextureBank<'a> = texture_bank::TextureBank<'a>;
 
+// Sprites are drawn from the largest priority value to the smallest, so a
+// smaller value appears in front. Ties are broken by the sprite number, the
+// smaller number in front. YSort additionally orders sprites of the same
+// priority by pos.y, the larger y in front.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum SpSortMode {
+    #[default]
+    Priority,
+    YSort,
+}
+
+// BoundingBox hits anywhere in the sprite's rect, PixelAccurate only on
+// its non-transparent pixels.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum HitTestMode {
+    #[default]
+    BoundingBox,
+    PixelAccurate,
+}
+
+// Mega Drive style operators: sprite pixels of these palette indices darken
+// or brighten what is underneath instead of being drawn.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ShadowHighlight {
+    pub shadow_index: u8,
+    pub highlight_index: u8,
+}
+
+impl ShadowHighlight {
+    pub fn new(shadow_index: u8, highlight_index: u8) -> Self {
+        Self {
+            shadow_index,
+            highlight_index,
+        }
+    }
+
+    // Darkens or brightens dst if index is one of the operators.
+    pub(crate) fn apply(&self, dst: &mut Rgba<u8>, index: u8) -> bool {
+        if index != self.shadow_index && index != self.highlight_index {
+            return false;
+        }
+        for c in 0..3 {
+            dst[c] = if index == self.shadow_index {
+                dst[c] / 2
+            } else {
+                dst[c] / 2 + 128
+            };
+        }
+        true
+    }
+}
+
 pub struct SpResources<'a> {
     pub sp: Vec<ClassicSprite>,
     pub texture_bank: Rc<RefCell<&'a mut SpTextureBank<'a>>>,
     pub pixel_scale: i32,
     pub base_symmetry: SpSymmetry,
+    pub symmetry_scope: SymmetryScope,
+    pub sort_mode: SpSortMode,
+    pub window: Option<ClipWindow>,
+    pub brightness: i32,
+    pub shadow_highlight: Option<ShadowHighlight>,
+    slots: SpSlots,
 }
 
-use super::bgsp_common::{RgbaImage, imageops};
-use std::collections::BTreeMap;
+use super::bgsp_common::{self, Rgba, RgbaImage, BRIGHTNESS_MAX};
+pub use super::bgsp_common::{ClipWindow, WindowRegion, WindowMode, SymmetryScope};
+use std::cmp::Reverse;
 impl<'a> SpResources<'a> {
 
     pub fn with_base_symmetry(
@@ -31,6 +93,12 @@ impl<'a> SpResources<'a> {
             texture_bank,
             pixel_scale,
             base_symmetry,
+            symmetry_scope: SymmetryScope::default(),
+            sort_mode: SpSortMode::default(),
+            window: None,
+            brightness: 0,
+            shadow_highlight: None,
+            slots: SpSlots::new(max_sprites),
         }
     }
 
@@ -50,6 +118,49 @@ impl<'a> SpResources<'a> {
         self.sp.len()
     }
 
+    // Keeps the range (e.g. the HUD sprites) out of allocate(), use
+    // allocate_in() for it. Fails if any of them is reserved or allocated.
+    pub fn reserve(&mut self, range: Range<usize>) -> Result<(), SpHandleError> {
+        self.slots.reserve(range)
+    }
+
+    pub fn release_reservation(&mut self, range: Range<usize>) -> Result<(), SpHandleError> {
+        self.slots.release(range)
+    }
+
+    pub fn reservations(&self) -> &[Range<usize>] {
+        self.slots.reservations()
+    }
+
+    pub fn allocate(&mut self) -> Result<SpHandle, SpHandleError> {
+        self.slots.allocate()
+    }
+
+    pub fn allocate_in(&mut self, range: Range<usize>) -> Result<SpHandle, SpHandleError> {
+        self.slots.allocate_in(range)
+    }
+
+    // The sprite is reset to the default (invisible) one.
+    pub fn free(&mut self, handle: SpHandle) -> Result<(), SpHandleError> {
+        let sp_no = self.slots.free(handle)?;
+        self.sp[sp_no] = ClassicSprite::default();
+        Ok(())
+    }
+
+    pub fn is_valid(&self, handle: SpHandle) -> bool {
+        self.slots.is_valid(handle)
+    }
+
+    pub fn num_allocated(&self) -> usize {
+        self.slots.num_allocated()
+    }
+
+    // As sp() but through a handle, an error once it has been freed.
+    pub fn sp_handle(&mut self, handle: SpHandle) -> Result<&mut ClassicSprite, SpHandleError> {
+        let sp_no = self.slots.check(handle)?;
+        Ok(&mut self.sp[sp_no])
+    }
+
     pub const fn pixel_scale(&self) -> i32 {
         self.pixel_scale
     }
@@ -62,29 +173,235 @@ impl<'a> SpResources<'a> {
         self.base_symmetry = base_symmetry;
     }
 
+    pub const fn symmetry_scope(&self) -> SymmetryScope {
+        self.symmetry_scope
+    }
+
+    // With SymmetryScope::Screen the sprite positions stay unrotated and
+    // rendering() returns the rotated view.
+    pub fn set_symmetry_scope(&mut self, symmetry_scope: SymmetryScope) {
+        self.symmetry_scope = symmetry_scope;
+    }
+
+    pub fn screen_symmetry(&self) -> SpSymmetry {
+        match self.symmetry_scope {
+            SymmetryScope::Texture => SpSymmetry::Normal,
+            SymmetryScope::Screen => self.base_symmetry,
+        }
+    }
+
+    fn sprite_symmetry(&self, a_sp: &ClassicSprite) -> SpSymmetry {
+        match self.symmetry_scope {
+            SymmetryScope::Texture => self.base_symmetry.compose(a_sp.symmetry),
+            SymmetryScope::Screen => a_sp.symmetry.followed_by(self.base_symmetry),
+        }
+    }
+
+    pub const fn sort_mode(&self) -> SpSortMode {
+        self.sort_mode
+    }
+
+    pub fn set_sort_mode(&mut self, sort_mode: SpSortMode) {
+        self.sort_mode = sort_mode;
+    }
+
+    pub fn window(&self) -> Option<&ClipWindow> {
+        self.window.as_ref()
+    }
+
+    pub fn set_window(&mut self, window: Option<ClipWindow>) {
+        self.window = window;
+    }
+
+    pub const fn brightness(&self) -> i32 {
+        self.brightness
+    }
+
+    // -BRIGHTNESS_MAX (black) ..= BRIGHTNESS_MAX (white), applied in
+    // rendering() and the Compositor.
+    pub fn set_brightness(&mut self, brightness: i32) {
+        self.brightness = brightness.clamp(-BRIGHTNESS_MAX, BRIGHTNESS_MAX);
+    }
+
+    pub const fn shadow_highlight(&self) -> Option<ShadowHighlight> {
+        self.shadow_highlight
+    }
+
+    // Takes effect in rendering() and the Compositor.
+    pub fn set_shadow_highlight(&mut self, shadow_highlight: Option<ShadowHighlight>) {
+        self.shadow_highlight = shadow_highlight;
+    }
+
+    // Sprite numbers in drawing order, back to front.
+    pub fn drawing_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.sp.len()).collect();
+        match self.sort_mode {
+            SpSortMode::Priority => {
+                order.sort_unstable_by_key(|&idx| (Reverse(self.sp[idx].priority), Reverse(idx)));
+            }
+            SpSortMode::YSort => {
+                order.sort_unstable_by_key(|&idx| (Reverse(self.sp[idx].priority), self.sp[idx].pos.y, Reverse(idx)));
+            }
+        }
+        order
+    }
+
+    pub(crate) fn sprite_texture(&self, sp_no: usize, view_w: i32, view_h: i32) -> Option<Rc<RgbaImage>> {
+        let a_sp = &self.sp[sp_no];
+        if !a_sp.visible
+        || a_sp.pos.x < -72 || a_sp.pos.x >= view_w + 8
+        || a_sp.pos.y < -72 || a_sp.pos.y >= view_h + 8 {
+            return None;
+        }
+        let symmetry = self.sprite_symmetry(a_sp);
+        self.texture_bank.borrow_mut().texture(a_sp.code, a_sp.palette, symmetry)
+    }
+
+    // Top-left of a sprite texture of size (in view pixels) in the
+    // rotated view, the view size is the unrotated one.
+    pub(crate) fn sprite_screen_pos(&self, sp_no: usize, size: (i32, i32), view_w: i32, view_h: i32) -> (i32, i32) {
+        let pos = self.sp[sp_no].pos;
+        let symmetry = self.screen_symmetry();
+        let (w, h) = symmetry.transform_size(size);
+        let rect = symmetry.transform_rect([pos.x, pos.y, w.max(1), h.max(1)], (view_w, view_h));
+        (rect[0], rect[1])
+    }
+
+    pub(crate) fn sprite_index_texture(&self, sp_no: usize) -> Option<Rc<RgbaImage>> {
+        let a_sp = &self.sp[sp_no];
+        let symmetry = self.sprite_symmetry(a_sp);
+        self.texture_bank.borrow_mut().index_texture(a_sp.code, symmetry)
+    }
+
+    fn hits(&self, sp_no: usize, x: i32, y: i32, mode: HitTestMode) -> bool {
+        let a_sp = &self.sp[sp_no];
+        if !a_sp.visible {
+            return false;
+        }
+        let symmetry = self.sprite_symmetry(a_sp);
+        let Some(t) = self.texture_bank.borrow_mut().texture(a_sp.code, a_sp.palette, symmetry) else {
+            return false;
+        };
+        let scale = self.texture_bank.borrow().pixel_scale();
+        let screen_symmetry = self.screen_symmetry();
+        let size = screen_symmetry.transform_size((t.width() as i32 / scale, t.height() as i32 / scale));
+        let (lx, ly) = (x - a_sp.pos.x, y - a_sp.pos.y);
+        if lx < 0 || lx >= size.0 || ly < 0 || ly >= size.1 {
+            return false;
+        }
+        match mode {
+            HitTestMode::BoundingBox => true,
+            HitTestMode::PixelAccurate => {
+                let (tx, ty) = screen_symmetry.transform_pos((lx, ly), size);
+                t.get_pixel((tx * scale) as u32, (ty * scale) as u32)[3] != 0
+            }
+        }
+    }
+
+    // The frontmost visible sprite at (x, y), in the coordinates of
+    // ClassicSprite::pos.
+    pub fn sprite_at(&self, x: i32, y: i32, mode: HitTestMode) -> Option<usize> {
+        self.drawing_order().into_iter().rev().find(|&sp_no| self.hits(sp_no, x, y, mode))
+    }
+
+    // All the visible sprites at (x, y), front to back.
+    pub fn sprites_at(&self, x: i32, y: i32, mode: HitTestMode) -> Vec<usize> {
+        self.drawing_order().into_iter().rev().filter(|&sp_no| self.hits(sp_no, x, y, mode)).collect()
+    }
+
     pub fn rendering(&mut self, view_w: i32, view_h: i32) -> RgbaImage {
-        let mut priority_map = BTreeMap::new();
-        let mut image_buffer = RgbaImage::new((view_w * self.pixel_scale) as u32, (view_h * self.pixel_scale) as u32);
-        for (idx, a_sp) in self.sp.iter().enumerate() {
-            priority_map.insert((a_sp.priority << 12) + idx as i32, idx);
+        self.pixel_scale = self.texture_bank.borrow().pixel_scale();
+        let (screen_w, screen_h) = self.screen_symmetry().transform_size((view_w, view_h));
+        let mut image_buffer = RgbaImage::new((screen_w * self.pixel_scale) as u32, (screen_h * self.pixel_scale) as u32);
+        for idx in self.drawing_order() {
+            if let Some(t) = self.sprite_texture(idx, view_w, view_h) {
+                let size = (t.width() as i32 / self.pixel_scale, t.height() as i32 / self.pixel_scale);
+                let (x, y) = self.sprite_screen_pos(idx, size, view_w, view_h);
+                self.draw_sprite(&mut image_buffer, &t, idx, (x * self.pixel_scale, y * self.pixel_scale));
+            }
         }
-        for (_priority, idx) in priority_map.iter().rev() {
-            let a_sp = &self.sp[*idx];
-            if !a_sp.visible
-            || a_sp.pos.x < -72 || a_sp.pos.x >= view_w + 8
-            || a_sp.pos.y < -72 || a_sp.pos.y >= view_h + 8 {
+        image_buffer
+    }
+
+    // Draws with the sprite's mosaic, tint, blend_mode and opacity, the
+    // brightness and shadow_highlight, clipped by the window, as the
+    // Compositor does. Mosaic blocks are aligned to the view.
+    fn draw_sprite(&self, image: &mut RgbaImage, texture: &RgbaImage, sp_no: usize, dst_pos: (i32, i32)) {
+        let a_sp = &self.sp[sp_no];
+        let index_texture = self.shadow_highlight.and_then(|_| self.sprite_index_texture(sp_no));
+        let operators = index_texture.as_deref().zip(self.shadow_highlight);
+        let (dst_w, dst_h) = (image.width() as i32, image.height() as i32);
+        let screen_view = (dst_w / self.pixel_scale, dst_h / self.pixel_scale);
+        let to_view = self.screen_symmetry().inverse();
+        let block = a_sp.mosaic.max(1) * self.pixel_scale;
+        let tinted = !a_sp.tint.is_identity();
+        for j in 0..texture.height() as i32 {
+            let dy = dst_pos.1 + j;
+            if dy < 0 || dy >= dst_h {
                 continue;
             }
-            let symmetry = self.base_symmetry.compose(a_sp.symmetry);
-            if let Some(t) = self.texture_bank.borrow_mut().texture(a_sp.code, a_sp.palette, symmetry) {
-                imageops::overlay(
-                    &mut image_buffer,
-                    &*t,
-                    (a_sp.pos.x * self.pixel_scale as i32) as i64,
-                    (a_sp.pos.y * self.pixel_scale as i32) as i64,
-                );
+            let sy = (dy - dy % block - dst_pos.1).max(0);
+            for i in 0..texture.width() as i32 {
+                let dx = dst_pos.0 + i;
+                if dx < 0 || dx >= dst_w {
+                    continue;
+                }
+                let sx = (dx - dx % block - dst_pos.0).max(0);
+                if let Some(window) = &self.window {
+                    // windows are given in the unrotated view
+                    let (wx, wy) = to_view.transform_pos((dx / self.pixel_scale, dy / self.pixel_scale), screen_view);
+                    if !window.shows(wx, wy) {
+                        continue;
+                    }
+                }
+                if let Some((index_texture, sh)) = operators {
+                    let index = index_texture.get_pixel(sx as u32, sy as u32)[0];
+                    if sh.apply(image.get_pixel_mut(dx as u32, dy as u32), index) {
+                        continue;
+                    }
+                }
+                let src = *texture.get_pixel(sx as u32, sy as u32);
+                let src = if tinted { a_sp.tint.apply(src) } else { src };
+                let src = bgsp_common::brighten(src, self.brightness);
+                let dst = image.get_pixel_mut(dx as u32, dy as u32);
+                bgsp_common::blend_pixel(dst, src, a_sp.blend_mode, a_sp.opacity);
             }
         }
-        image_buffer
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::texture_bank::test_texture_bank;
+
+    #[test]
+    fn sp_handle_after_free() {
+        let mut texture_bank = test_texture_bank(vec![None; 1]);
+        let mut sp_resources = SpResources::new(4, Rc::new(RefCell::new(&mut texture_bank)));
+        let handle = sp_resources.allocate().unwrap();
+        sp_resources.sp_handle(handle).unwrap().xy(3, 4).visible(true);
+        assert_eq!(sp_resources.free(handle), Ok(()));
+        assert!(!sp_resources.sp[handle.index()].visible);
+        assert_eq!(sp_resources.sp_handle(handle).err(), Some(SpHandleError::Stale(handle)));
+        assert_eq!(sp_resources.free(handle), Err(SpHandleError::Stale(handle)));
+    }
+
+    #[test]
+    fn rendering_applies_shadow_highlight() {
+        const SOLID: [u64; 8] = [0xc8c8_c8c8_c8c8_c8c8; 8];
+        // shadow, highlight and color 0x10 in the left half, transparent in the right
+        const OPERATORS: [u64; 8] = [0x0405_1010_0000_0000; 8];
+        let mut texture_bank = test_texture_bank(vec![Some((1, 1, &SOLID[..])), Some((1, 1, &OPERATORS[..]))]);
+        let mut sp_resources = SpResources::new(2, Rc::new(RefCell::new(&mut texture_bank)));
+        sp_resources.set_shadow_highlight(Some(ShadowHighlight::new(4, 5)));
+        sp_resources.sp(0).code(1).visible(true);
+        sp_resources.sp(1).code(0).visible(true);
+        let image = sp_resources.rendering(8, 8);
+        let colors: Vec<u8> = (0..5).map(|x| image.get_pixel(x, 0)[0]).collect();
+        assert_eq!(colors, vec![100, 228, 0x10, 0x10, 200]);
+        sp_resources.set_brightness(-BRIGHTNESS_MAX);
+        let image = sp_resources.rendering(8, 8);
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0, 255]);
+    }
+}
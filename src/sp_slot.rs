@@ -0,0 +1,202 @@
+use std::fmt;
+use std::ops::Range;
+
+// A sprite slot allocated by SpResources::allocate. The generation changes
+// when the slot is freed, so a handle kept after free() is detected.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct SpHandle {
+    index: usize,
+    generation: u32,
+}
+
+impl SpHandle {
+    // The sprite number, as for SpResources::sp.
+    pub const fn index(&self) -> usize {
+        self.index
+    }
+
+    pub const fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SpHandleError {
+    Stale(SpHandle),
+    NoFreeSlot,
+    OutOfRange(Range<usize>),
+    Overlap(Range<usize>),
+    NotReserved(Range<usize>),
+}
+
+impl fmt::Display for SpHandleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpHandleError::Stale(handle) => write!(f, "stale handle to sprite {} (generation {})", handle.index, handle.generation),
+            SpHandleError::NoFreeSlot => write!(f, "no free sprite slot"),
+            SpHandleError::OutOfRange(range) => write!(f, "sprites {:?} out of range", range),
+            SpHandleError::Overlap(range) => write!(f, "sprites {:?} overlap a reservation or allocated sprites", range),
+            SpHandleError::NotReserved(range) => write!(f, "sprites {:?} are not a reservation", range),
+        }
+    }
+}
+
+impl std::error::Error for SpHandleError {}
+
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    generation: u32,
+    allocated: bool,
+    reserved: bool,
+}
+
+pub(crate) struct SpSlots {
+    slots: Vec<Slot>,
+    reservations: Vec<Range<usize>>,
+}
+
+impl SpSlots {
+    pub(crate) fn new(num_slots: usize) -> Self {
+        Self {
+            slots: vec![Slot::default(); num_slots],
+            reservations: Vec::new(),
+        }
+    }
+
+    fn check_range(&self, range: &Range<usize>) -> Result<(), SpHandleError> {
+        if range.start >= range.end || range.end > self.slots.len() {
+            return Err(SpHandleError::OutOfRange(range.clone()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn reserve(&mut self, range: Range<usize>) -> Result<(), SpHandleError> {
+        self.check_range(&range)?;
+        if self.slots[range.clone()].iter().any(|slot| slot.reserved || slot.allocated) {
+            return Err(SpHandleError::Overlap(range));
+        }
+        for slot in &mut self.slots[range.clone()] {
+            slot.reserved = true;
+        }
+        self.reservations.push(range);
+        Ok(())
+    }
+
+    // The slots allocated in the range stay allocated.
+    pub(crate) fn release(&mut self, range: Range<usize>) -> Result<(), SpHandleError> {
+        let n = self.reservations.iter().position(|r| *r == range).ok_or(SpHandleError::NotReserved(range))?;
+        for slot in &mut self.slots[self.reservations.remove(n)] {
+            slot.reserved = false;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn reservations(&self) -> &[Range<usize>] {
+        &self.reservations
+    }
+
+    fn allocate_at(&mut self, index: usize) -> SpHandle {
+        let slot = &mut self.slots[index];
+        slot.allocated = true;
+        SpHandle {
+            index,
+            generation: slot.generation,
+        }
+    }
+
+    // The first free slot out of the reservations.
+    pub(crate) fn allocate(&mut self) -> Result<SpHandle, SpHandleError> {
+        let index = self.slots.iter()
+            .position(|slot| !slot.allocated && !slot.reserved)
+            .ok_or(SpHandleError::NoFreeSlot)?;
+        Ok(self.allocate_at(index))
+    }
+
+    // The first free slot in range, reserved or not.
+    pub(crate) fn allocate_in(&mut self, range: Range<usize>) -> Result<SpHandle, SpHandleError> {
+        self.check_range(&range)?;
+        let index = self.slots[range.clone()].iter()
+            .position(|slot| !slot.allocated)
+            .ok_or(SpHandleError::NoFreeSlot)?;
+        Ok(self.allocate_at(range.start + index))
+    }
+
+    pub(crate) fn is_valid(&self, handle: SpHandle) -> bool {
+        self.slots.get(handle.index).is_some_and(|slot| slot.allocated && slot.generation == handle.generation)
+    }
+
+    pub(crate) fn check(&self, handle: SpHandle) -> Result<usize, SpHandleError> {
+        if self.is_valid(handle) {
+            Ok(handle.index)
+        } else {
+            Err(SpHandleError::Stale(handle))
+        }
+    }
+
+    pub(crate) fn free(&mut self, handle: SpHandle) -> Result<usize, SpHandleError> {
+        let index = self.check(handle)?;
+        let slot = &mut self.slots[index];
+        slot.allocated = false;
+        slot.generation = slot.generation.wrapping_add(1);
+        Ok(index)
+    }
+
+    pub(crate) fn num_allocated(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.allocated).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_handle_after_free() {
+        let mut slots = SpSlots::new(4);
+        let handle = slots.allocate().unwrap();
+        assert_eq!(slots.free(handle), Ok(handle.index()));
+        assert!(!slots.is_valid(handle));
+        assert_eq!(slots.check(handle), Err(SpHandleError::Stale(handle)));
+        assert_eq!(slots.free(handle), Err(SpHandleError::Stale(handle)));
+        // the slot is reused with a new generation, the old handle stays stale
+        let reused = slots.allocate().unwrap();
+        assert_eq!(reused.index(), handle.index());
+        assert_ne!(reused.generation(), handle.generation());
+        assert!(slots.is_valid(reused));
+        assert_eq!(slots.check(handle), Err(SpHandleError::Stale(handle)));
+    }
+
+    #[test]
+    fn reserve_overlapping_allocated() {
+        let mut slots = SpSlots::new(8);
+        let handle = slots.allocate_in(3..4).unwrap();
+        assert_eq!(slots.reserve(2..5), Err(SpHandleError::Overlap(2..5)));
+        slots.free(handle).unwrap();
+        assert_eq!(slots.reserve(2..5), Ok(()));
+        assert_eq!(slots.reserve(4..6), Err(SpHandleError::Overlap(4..6)));
+        assert_eq!(slots.reserve(6..9), Err(SpHandleError::OutOfRange(6..9)));
+    }
+
+    #[test]
+    fn allocate_skips_reservations() {
+        let mut slots = SpSlots::new(6);
+        slots.reserve(0..2).unwrap();
+        slots.reserve(3..5).unwrap();
+        let indices: Vec<usize> = (0..2).map(|_| slots.allocate().unwrap().index()).collect();
+        assert_eq!(indices, vec![2, 5]);
+        assert_eq!(slots.allocate(), Err(SpHandleError::NoFreeSlot));
+        assert_eq!(slots.allocate_in(3..5).unwrap().index(), 3);
+        assert_eq!(slots.num_allocated(), 3);
+    }
+
+    #[test]
+    fn release_not_reserved() {
+        let mut slots = SpSlots::new(6);
+        assert_eq!(slots.release(0..2), Err(SpHandleError::NotReserved(0..2)));
+        slots.reserve(0..4).unwrap();
+        assert_eq!(slots.release(0..2), Err(SpHandleError::NotReserved(0..2)));
+        assert_eq!(slots.release(0..4), Ok(()));
+        assert!(slots.reservations().is_empty());
+        assert_eq!(slots.allocate().unwrap().index(), 0);
+    }
+}
@@ -3,7 +3,7 @@ use std::rc::Rc;
 
 use super::bgsp_common::{
     self,
-    PATTERN_SIZE, NUM_PALETTE_COL,
+    PATTERN_SIZE, NUM_PALETTE_COL, PIXEL_SCALE_MAX,
     Rgba, RgbaImage,
     Code, Palette, Symmetry,
 };
@@ -16,6 +16,8 @@ pub struct TextureBank<'a> {
     palette_tbl: &'a [[Rgba<u8>; NUM_PALETTE_COL]],
     pixel_scale: i32,
     texture_cache: BTreeMap<(Code, Palette, Symmetry), RcTexture>,
+    index_cache: BTreeMap<(Code, Symmetry), RcTexture>,
+    generation: u32,
 }
 
 impl<'a> TextureBank<'a> {
@@ -29,6 +31,8 @@ impl<'a> TextureBank<'a> {
             palette_tbl,
             pixel_scale,
             texture_cache: BTreeMap::new(),
+            index_cache: BTreeMap::new(),
+            generation: 0,
         }
     }
 
@@ -36,37 +40,91 @@ impl<'a> TextureBank<'a> {
         self.pixel_scale
     }
 
+    // Changes whenever the textures may look different, the users
+    // (BgResources) compare it to redraw what they have rendered.
+    pub const fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn set_pattern_tbl(&mut self, pattern_tbl: &'a [Option<(u32, u32, &'a [u64])>]) {
+        self.pattern_tbl = pattern_tbl;
+        self.clear_cache();
+    }
+
+    pub fn set_palette_tbl(&mut self, palette_tbl: &'a [[Rgba<u8>; NUM_PALETTE_COL]]) {
+        self.palette_tbl = palette_tbl;
+        self.clear_cache();
+    }
+
+    pub fn set_pixel_scale(&mut self, pixel_scale: i32) {
+        self.pixel_scale = pixel_scale.clamp(1, PIXEL_SCALE_MAX);
+        self.clear_cache();
+    }
+
     pub fn clear_cache(&mut self) {
-        self.texture_cache.clear()
+        self.texture_cache.clear();
+        self.index_cache.clear();
+        self.generation = self.generation.wrapping_add(1);
     }
 
     pub fn cashed_num(&self) -> usize {
         self.texture_cache.len()
     }
 
+    fn build(&self, pattern_no: Code, color_tbl: &[Rgba<u8>], symmetry: Symmetry) -> Option<Texture> {
+        let pattern_info = self.pattern_tbl[pattern_no as usize]?;
+        let scale = self.pixel_scale as u32;
+        let size = if !symmetry.has_rotate90() {
+            (pattern_info.0, pattern_info.1)
+        } else {
+            (pattern_info.1, pattern_info.0)
+        };
+        if size.0 > 0 && size.1 > 0 {
+            let mut buffer = Texture::new(size.0 * PATTERN_SIZE as u32 * scale, size.1 * PATTERN_SIZE as u32 * scale);
+            bgsp_common::draw((pattern_info.0, pattern_info.1), pattern_info.2, color_tbl, symmetry, (0, 0), (scale, scale), &mut buffer);
+            Some(buffer)
+        } else {
+            None
+        }
+    }
+
     pub fn texture(&mut self, pattern_no: Code, palette_no: Palette, symmetry: Symmetry) -> Option<RcTexture> {
         if let Some(result) = self.texture_cache.get(&(pattern_no, palette_no, symmetry)) {
             Some(result.clone())
         } else {
-            if let Some(pattern_info) = self.pattern_tbl[pattern_no as usize] {
-                let scale = self.pixel_scale as u32;
-                let size = if !symmetry.has_rotate90() {
-                    (pattern_info.0, pattern_info.1)
-                } else {
-                    (pattern_info.1, pattern_info.0)
-                };
-                if size.0 > 0 && size.1 > 0 {
-                    let mut buffer = Texture::new(size.0 * PATTERN_SIZE as u32 * scale, size.1 * PATTERN_SIZE as u32 * scale);
-                    bgsp_common::draw((pattern_info.0, pattern_info.1), pattern_info.2, &self.palette_tbl[palette_no as usize], symmetry, (0, 0), (scale, scale), &mut buffer);
-                    let rc_texture = Rc::new(buffer);
-                    let _ = self.texture_cache.insert((pattern_no, palette_no, symmetry), rc_texture.clone());
-                    Some(rc_texture)
-                } else {
-                    None
-                }
-            } else {
-                None
+            let buffer = self.build(pattern_no, &self.palette_tbl[palette_no as usize], symmetry)?;
+            let rc_texture = Rc::new(buffer);
+            let _ = self.texture_cache.insert((pattern_no, palette_no, symmetry), rc_texture.clone());
+            Some(rc_texture)
+        }
+    }
+
+    // The palette index of each pixel in the red channel, laid out as texture().
+    pub fn index_texture(&mut self, pattern_no: Code, symmetry: Symmetry) -> Option<RcTexture> {
+        if let Some(result) = self.index_cache.get(&(pattern_no, symmetry)) {
+            Some(result.clone())
+        } else {
+            let mut index_tbl = [Rgba([0, 0, 0, 255]); NUM_PALETTE_COL];
+            for (i, color) in index_tbl.iter_mut().enumerate() {
+                color[0] = i as u8;
             }
+            let buffer = self.build(pattern_no, &index_tbl, symmetry)?;
+            let rc_texture = Rc::new(buffer);
+            let _ = self.index_cache.insert((pattern_no, symmetry), rc_texture.clone());
+            Some(rc_texture)
         }
     }
 }
+
+// A TextureBank for the unit tests, with one palette where color n is
+// Rgba([n, n, n, 255]) but color 0 which is transparent.
+#[cfg(test)]
+pub(crate) fn test_texture_bank(pattern_tbl: Vec<Option<(u32, u32, &'static [u64])>>) -> TextureBank<'static> {
+    let mut palette = [Rgba([0, 0, 0, 0]); NUM_PALETTE_COL];
+    for (n, color) in palette.iter_mut().enumerate().skip(1) {
+        *color = Rgba([n as u8, n as u8, n as u8, 255]);
+    }
+    let pattern_tbl = Box::leak(pattern_tbl.into_boxed_slice());
+    let palette_tbl = Box::leak(vec![palette; 1].into_boxed_slice());
+    TextureBank::new(pattern_tbl, palette_tbl, 1)
+}